@@ -0,0 +1,98 @@
+//! tokio's async I/O traits for `File`, so it can stand in for `tokio::fs::File`.
+//!
+//! The data is in memory, so every operation completes on the first poll; only
+//! an injected delay makes a read or write return `Pending`. `poll_flush` and
+//! `poll_shutdown` write the backing file, if any, synchronously.
+
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+
+use crate::File;
+
+impl AsyncRead for File {
+  fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    self.get_mut().poll_faulty(cx, |file| {
+      let n = File::read(file, buf.initialize_unfilled())?;
+      buf.advance(n);
+      Ok(())
+    })
+  }
+}
+
+impl AsyncWrite for File {
+  fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+    self.get_mut().poll_faulty(cx, |file| File::write(file, buf).map_err(io::Error::from))
+  }
+
+  fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Poll::Ready(File::flush(self.get_mut()).map_err(io::Error::from))
+  }
+
+  /// Flushes like `tokio::fs::File` does; the file stays open.
+  fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    self.poll_flush(cx)
+  }
+}
+
+impl AsyncSeek for File {
+  fn start_seek(self: Pin<&mut Self>, pos: SeekFrom) -> io::Result<()> {
+    File::seek(self.get_mut(), pos).map(|_| ()).map_err(io::Error::from)
+  }
+
+  /// The seek already happened in `start_seek`; this reports where it landed.
+  fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+    Poll::Ready(Ok(self.pos as u64))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::{Duration, Instant};
+
+  use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+  use crate::fault::{Fault, FaultPlan};
+
+  fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(future)
+  }
+
+  #[test]
+  fn round_trip() {
+    let mut f = File::new("f");
+    f.open().unwrap();
+    let read = block_on(async {
+      f.write_all(b"hello async").await?;
+      f.shutdown().await?;
+      AsyncSeekExt::seek(&mut f, SeekFrom::Start(6)).await?;
+      let mut read = String::new();
+      f.read_to_string(&mut read).await?;
+      Ok::<_, io::Error>(read)
+    });
+    assert_eq!(read.unwrap(), "async");
+  }
+
+  #[test]
+  fn errors_map_to_io() {
+    let mut f = File::new("f");
+    let err = block_on(f.write_all(b"x")).unwrap_err();
+    assert_eq!(err.kind(), io::Error::from(crate::FileError::NotOpen).kind());
+  }
+
+  #[test]
+  fn injected_delay_is_pending_not_sleeping() {
+    let mut f = File::new("f");
+    f.open().unwrap();
+    let delay = Duration::from_millis(30);
+    f.set_faults(Some(FaultPlan { delay_rate: 1.0, delay, ..FaultPlan::default() }));
+    let start = Instant::now();
+    block_on(f.write_all(b"late")).unwrap();
+    assert!(start.elapsed() >= delay);
+    assert_eq!(f.faults().unwrap().log(), [Fault::Delayed(delay)]);
+    assert_eq!(&*f.contents(), b"late");
+  }
+}
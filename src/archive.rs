@@ -0,0 +1,515 @@
+//! A ustar/pax archive format for `File`s that standard `tar` can list and extract.
+//!
+//! Each file entry is preceded by a pax extended header carrying its `FileState`,
+//! whether it is encrypted, its creation time and a CRC-32 of its data as
+//! `user.memfs.*` extended attributes, which GNU tar and bsdtar skip quietly
+//! unless asked to restore xattrs. The file's own extended attributes and
+//! access time ride along the same way.
+//! Symlinks and hard links become ustar link entries; the first name of hard
+//! linked data carries it and later names point back to that one.
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::time::SystemTime;
+
+use crate::memfs::{DirEntry, MemFs};
+use crate::perm::Permissions;
+use crate::state::FileState;
+use crate::time::{from_unix_secs, unix_secs, Timestamps};
+use crate::xattr::RESERVED_PREFIX;
+use crate::{File, FileError};
+
+const BLOCK: usize = 512;
+const STATE_KEY: &str = "SCHILY.xattr.user.memfs.state";
+const CRC_KEY: &str = "SCHILY.xattr.user.memfs.crc32";
+const CREATED_KEY: &str = "SCHILY.xattr.user.memfs.created";
+const ENCRYPTED_KEY: &str = "SCHILY.xattr.user.memfs.encrypted";
+const XATTR_PREFIX: &str = "SCHILY.xattr.";
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum EntryKind {
+  File,
+  Dir,
+  Symlink,
+  HardLink,
+}
+
+/// One unpacked archive member; `file` is `None` for directories and links.
+#[derive(Debug)]
+pub struct Entry {
+  pub path: String,
+  pub kind: EntryKind,
+  pub file: Option<File>,
+  /// A symlink's target, or the archive path a hard link shares data with.
+  pub link: Option<String>,
+}
+
+pub struct ArchiveWriter<W: Write> {
+  out: W,
+}
+
+/// Reads one entry at a time, so only the current entry's data is ever in memory.
+pub struct ArchiveReader<R: Read> {
+  input: R,
+  done: bool,
+}
+
+/// CRC-32 (IEEE), as used by zip and gzip.
+pub fn crc32(data: &[u8]) -> u32 {
+  let mut crc = !0u32;
+  for &byte in data {
+    crc ^= byte as u32;
+    for _ in 0..8 {
+      crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+    }
+  }
+  !crc
+}
+
+fn corrupt(what: &str) -> FileError {
+  FileError::Corrupt(format!("archive: {}", what))
+}
+
+fn put_str(field: &mut [u8], value: &str) {
+  let bytes = value.as_bytes();
+  let n = bytes.len().min(field.len());
+  field[..n].copy_from_slice(&bytes[..n]);
+}
+
+fn put_octal(field: &mut [u8], value: u64) {
+  let digits = format!("{:0width$o}", value, width = field.len() - 1);
+  put_str(field, &digits);
+}
+
+fn get_str(field: &[u8]) -> String {
+  let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+  String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+fn get_octal(field: &[u8]) -> Result<u64, FileError> {
+  let text = get_str(field);
+  let text = text.trim_matches(|c: char| c == ' ' || c == '\0');
+  if text.is_empty() {
+    return Ok(0);
+  }
+  u64::from_str_radix(text, 8).map_err(|_| corrupt("bad octal field"))
+}
+
+fn header_checksum(header: &[u8; BLOCK]) -> u64 {
+  header.iter().enumerate().map(|(i, &b)| if (148..156).contains(&i) { b' ' as u64 } else { b as u64 }).sum()
+}
+
+fn padding(len: usize) -> usize {
+  (BLOCK - len % BLOCK) % BLOCK
+}
+
+/// A pax record is `"<len> <key>=<value>\n"`, where `len` counts itself.
+fn pax_record(key: &str, value: &str) -> String {
+  let body = format!(" {}={}\n", key, value);
+  let mut len = body.len() + 1;
+  while len.to_string().len() + body.len() != len {
+    len += 1;
+  }
+  format!("{}{}", len, body)
+}
+
+/// Long link targets go in the pax header, like long paths.
+fn link_records(target: &str) -> String {
+  if target.len() > 100 {
+    pax_record("linkpath", target)
+  } else {
+    String::new()
+  }
+}
+
+fn parse_pax(data: &[u8]) -> Result<HashMap<String, String>, FileError> {
+  let mut records = HashMap::new();
+  let mut rest = data;
+  while !rest.is_empty() {
+    let space = rest.iter().position(|&b| b == b' ').ok_or_else(|| corrupt("bad pax record"))?;
+    let len: usize = std::str::from_utf8(&rest[..space]).ok()
+      .and_then(|s| s.parse().ok())
+      .filter(|&len| len >= space + 2 && len <= rest.len())
+      .ok_or_else(|| corrupt("bad pax record length"))?;
+    if rest[len - 1] != b'\n' {
+      return Err(corrupt("pax record not terminated by a newline"));
+    }
+    let record = String::from_utf8_lossy(&rest[space + 1..len - 1]).into_owned();
+    let (key, value) = record.split_once('=').ok_or_else(|| corrupt("bad pax record"))?;
+    records.insert(key.to_string(), value.to_string());
+    rest = &rest[len..];
+  }
+  Ok(records)
+}
+
+impl<W: Write> ArchiveWriter<W> {
+  pub fn new(out: W) -> ArchiveWriter<W> {
+    ArchiveWriter { out }
+  }
+
+  fn write_header(&mut self, path: &str, typeflag: u8, size: usize, perms: &Permissions, mtime: u64, link: &str) -> Result<(), FileError> {
+    let mut header = [0u8; BLOCK];
+    put_str(&mut header[0..100], path);
+    put_str(&mut header[157..257], link);
+    put_octal(&mut header[100..108], perms.mode as u64);
+    put_octal(&mut header[108..116], 0);
+    put_octal(&mut header[116..124], 0);
+    put_octal(&mut header[124..136], size as u64);
+    put_octal(&mut header[136..148], mtime);
+    header[156] = typeflag;
+    put_str(&mut header[257..263], "ustar\0");
+    put_str(&mut header[263..265], "00");
+    put_str(&mut header[265..297], &perms.owner);
+    put_str(&mut header[297..329], &perms.group);
+    let sum = header_checksum(&header);
+    put_str(&mut header[148..156], &format!("{:06o}\0 ", sum));
+    self.out.write_all(&header)?;
+    Ok(())
+  }
+
+  fn write_data(&mut self, data: &[u8]) -> Result<(), FileError> {
+    self.out.write_all(data)?;
+    self.out.write_all(&[0u8; BLOCK][..padding(data.len())])?;
+    Ok(())
+  }
+
+  /// Long paths go in the pax header; the ustar name field keeps a truncated copy.
+  fn write_pax(&mut self, path: &str, mut records: String, perms: &Permissions) -> Result<(), FileError> {
+    if path.len() > 100 {
+      records.push_str(&pax_record("path", path));
+    }
+    if records.is_empty() {
+      return Ok(());
+    }
+    let short = path.rsplit('/').next().unwrap_or(path);
+    self.write_header(&format!("PaxHeaders/{}", short), b'x', records.len(), perms, 0, "")?;
+    self.write_data(records.as_bytes())
+  }
+
+  pub fn append_dir(&mut self, path: &str) -> Result<(), FileError> {
+    let path = format!("{}/", path.trim_matches('/'));
+    let perms = Permissions { mode: 0o755, ..Permissions::default() };
+    self.write_pax(&path, String::new(), &perms)?;
+    self.write_header(&path, b'5', 0, &perms, 0, "")
+  }
+
+  pub fn append_symlink(&mut self, path: &str, target: &str) -> Result<(), FileError> {
+    let path = path.trim_start_matches('/');
+    let perms = Permissions { mode: 0o777, ..Permissions::default() };
+    self.write_pax(path, link_records(target), &perms)?;
+    self.write_header(path, b'2', 0, &perms, 0, target)
+  }
+
+  /// Records `path` as another name for the data already archived at `target`.
+  pub fn append_hard_link(&mut self, path: &str, target: &str, file: &File) -> Result<(), FileError> {
+    let (path, target) = (path.trim_start_matches('/'), target.trim_start_matches('/'));
+    self.write_pax(path, link_records(target), &file.perms)?;
+    self.write_header(path, b'1', 0, &file.perms, unix_secs(file.timestamps().modified), target)
+  }
+
+  pub fn append_file(&mut self, path: &str, file: &File) -> Result<(), FileError> {
+    let path = path.trim_start_matches('/');
+    let data = file.contents();
+    let times = file.timestamps();
+    let mut records = pax_record(STATE_KEY, &file.state.to_string())
+      + &pax_record(CRC_KEY, &format!("{:08x}", crc32(&data)))
+      + &pax_record(CREATED_KEY, &unix_secs(times.created).to_string())
+      + &pax_record("atime", &unix_secs(times.accessed).to_string());
+    if file.is_encrypted() {
+      records.push_str(&pax_record(ENCRYPTED_KEY, "1"));
+    }
+    for (key, value) in file.xattrs() {
+      records.push_str(&pax_record(&format!("{}{}", XATTR_PREFIX, key), value));
+    }
+    self.write_pax(path, records, &file.perms)?;
+    self.write_header(path, b'0', data.len(), &file.perms, unix_secs(times.modified), "")?;
+    self.write_data(&data)
+  }
+
+  /// Writes the end-of-archive marker and hands back the output.
+  pub fn finish(mut self) -> Result<W, FileError> {
+    self.out.write_all(&[0u8; BLOCK * 2])?;
+    self.out.flush()?;
+    Ok(self.out)
+  }
+}
+
+impl<R: Read> ArchiveReader<R> {
+  pub fn new(input: R) -> ArchiveReader<R> {
+    ArchiveReader { input, done: false }
+  }
+
+  fn read_block(&mut self) -> Result<Option<[u8; BLOCK]>, FileError> {
+    let mut block = [0u8; BLOCK];
+    let mut filled = 0;
+    while filled < BLOCK {
+      let n = self.input.read(&mut block[filled..])?;
+      if n == 0 {
+        return if filled == 0 { Ok(None) } else { Err(corrupt("truncated header")) };
+      }
+      filled += n;
+    }
+    Ok(Some(block))
+  }
+
+  fn read_data(&mut self, size: usize) -> Result<Vec<u8>, FileError> {
+    let mut data = Vec::new();
+    data.try_reserve(size).map_err(|_| FileError::OutOfSpace)?;
+    (&mut self.input).take(size as u64).read_to_end(&mut data)?;
+    if data.len() != size {
+      return Err(corrupt("truncated entry"));
+    }
+    io_skip(&mut self.input, padding(size))?;
+    Ok(data)
+  }
+
+  /// The next file or directory, or `None` at the end of the archive.
+  pub fn next_entry(&mut self) -> Result<Option<Entry>, FileError> {
+    let mut pax = HashMap::new();
+    while !self.done {
+      let Some(header) = self.read_block()? else { break };
+      if header.iter().all(|&b| b == 0) {
+        self.done = true;
+        break;
+      }
+      if get_octal(&header[148..156])? != header_checksum(&header) {
+        return Err(corrupt("header checksum mismatch"));
+      }
+
+      let size = get_octal(&header[124..136])? as usize;
+      let typeflag = header[156];
+      let data = self.read_data(size)?;
+      let mut path = get_str(&header[0..100]);
+      let prefix = get_str(&header[345..500]);
+      if !prefix.is_empty() {
+        path = format!("{}/{}", prefix, path);
+      }
+      if let Some(long) = pax.remove("path") {
+        path = long;
+      }
+
+      match typeflag {
+        b'x' => pax.extend(parse_pax(&data)?),
+        b'5' => {
+          return Ok(Some(Entry { path: path.trim_end_matches('/').to_string(), kind: EntryKind::Dir, file: None, link: None }));
+        }
+        b'1' | b'2' => {
+          let kind = if typeflag == b'1' { EntryKind::HardLink } else { EntryKind::Symlink };
+          let link = pax.remove("linkpath").unwrap_or_else(|| get_str(&header[157..257]));
+          return Ok(Some(Entry { path, kind, file: None, link: Some(link) }));
+        }
+        b'0' | 0 => {
+          if let Some(expected) = pax.get(CRC_KEY)
+            && *expected != format!("{:08x}", crc32(&data))
+          {
+            return Err(corrupt(&format!("checksum mismatch for {}", path)));
+          }
+          let name = path.rsplit('/').next().unwrap_or(&path);
+          let mut file = File::new_with_data(name, &data);
+          file.perms = Permissions::new(&get_str(&header[265..297]), &get_str(&header[297..329]), get_octal(&header[100..108])? as u16);
+          if let Some(state) = pax.get(STATE_KEY) {
+            // A lock doesn't outlive the process that held it.
+            file.state = match state.parse::<FileState>()? {
+              FileState::Locked => FileState::Closed,
+              state => state,
+            };
+          }
+          file.encrypted = pax.get(ENCRYPTED_KEY).is_some_and(|v| v == "1");
+          let modified = from_unix_secs(get_octal(&header[136..148])?).ok_or_else(|| corrupt("bad mtime"))?;
+          let pax_time = |key: &str| -> Result<Option<SystemTime>, FileError> {
+            // pax times may carry a fraction; whole seconds are enough here.
+            pax.get(key).map(|v| {
+              let secs = v.split('.').next().unwrap_or(v);
+              secs.parse().ok().and_then(from_unix_secs).ok_or_else(|| corrupt(&format!("bad {} record", key)))
+            }).transpose()
+          };
+          file.set_timestamps(Timestamps {
+            created: pax_time(CREATED_KEY)?.unwrap_or(modified),
+            modified,
+            accessed: pax_time("atime")?.unwrap_or(modified),
+          });
+          for (key, value) in &pax {
+            if let Some(name) = key.strip_prefix(XATTR_PREFIX)
+              && !name.starts_with(RESERVED_PREFIX)
+            {
+              file.set_xattr(name, value)?;
+            }
+          }
+          return Ok(Some(Entry { path, kind: EntryKind::File, file: Some(file), link: None }));
+        }
+        // Global headers and devices carry nothing a `MemFs` can hold.
+        _ => pax.clear(),
+      }
+    }
+    Ok(None)
+  }
+}
+
+fn io_skip<R: Read>(input: &mut R, n: usize) -> Result<(), FileError> {
+  let skipped = std::io::copy(&mut input.take(n as u64), &mut std::io::sink())?;
+  if skipped as usize != n {
+    return Err(corrupt("truncated padding"));
+  }
+  Ok(())
+}
+
+impl<R: Read> Iterator for ArchiveReader<R> {
+  type Item = Result<Entry, FileError>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    match self.next_entry() {
+      Ok(entry) => entry.map(Ok),
+      Err(e) => {
+        self.done = true;
+        Some(Err(e))
+      }
+    }
+  }
+}
+
+/// Packs every file, directory and link of `fs` into an archive.
+pub fn write_fs<W: Write>(fs: &MemFs, out: W) -> Result<W, FileError> {
+  let mut writer = ArchiveWriter::new(out);
+  // The first path written for each piece of hard linked data.
+  let mut linked: HashMap<usize, String> = HashMap::new();
+  for (path, entry) in fs.walk() {
+    match entry {
+      DirEntry::Dir(_) => writer.append_dir(&path)?,
+      DirEntry::Symlink { target, .. } => writer.append_symlink(&path, target)?,
+      DirEntry::File(file) => match file.data.as_linked().filter(|l| l.count() > 1) {
+        Some(shared) => match linked.get(&shared.id()) {
+          Some(first) => writer.append_hard_link(&path, first, file)?,
+          None => {
+            writer.append_file(&path, file)?;
+            linked.insert(shared.id(), path);
+          }
+        },
+        None => writer.append_file(&path, file)?,
+      },
+    }
+  }
+  writer.finish()
+}
+
+/// Rebuilds a `MemFs` from an archive, creating missing parent directories.
+pub fn read_fs<R: Read>(input: R) -> Result<MemFs, FileError> {
+  let mut fs = MemFs::new();
+  for entry in ArchiveReader::new(input) {
+    let entry = entry?;
+    let path = format!("/{}", entry.path);
+    if entry.kind == EntryKind::Dir {
+      fs.mkdir_all(&path)?;
+      continue;
+    }
+    if let Some((parent, _)) = path.rsplit_once('/') {
+      fs.mkdir_all(parent)?;
+    }
+    match (entry.kind, entry.file, entry.link) {
+      (EntryKind::File, Some(file), _) => {
+        fs.insert(&path, file)?;
+      }
+      (EntryKind::Symlink, _, Some(target)) => fs.symlink(&target, &path)?,
+      (EntryKind::HardLink, _, Some(target)) => {
+        fs.link(&format!("/{}", target), &path)?;
+      }
+      _ => return Err(corrupt(&format!("incomplete entry for {}", path))),
+    }
+  }
+  Ok(fs)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::crypt::FileKey;
+
+  fn round_trip(fs: &MemFs) -> MemFs {
+    read_fs(&write_fs(fs, Vec::new()).unwrap()[..]).unwrap()
+  }
+
+  #[test]
+  fn files_dirs_and_metadata_round_trip() {
+    let mut fs = MemFs::new();
+    let deep = format!("/{}/file.txt", ["nested"; 20].join("/"));
+    fs.mkdir_all(deep.rsplit_once('/').unwrap().0).unwrap();
+    let f = fs.create(&deep).unwrap();
+    f.open().unwrap();
+    f.write(b"payload").unwrap();
+    f.chown("alice", "staff").unwrap();
+    f.chmod(0o640).unwrap();
+    f.set_xattr("user.tag", "blue").unwrap();
+    fs.mkdir("/empty").unwrap();
+
+    let back = round_trip(&fs);
+    let f = back.file(&deep).unwrap();
+    assert_eq!(&*f.contents(), b"payload");
+    assert_eq!((f.perms.owner.as_str(), f.perms.mode), ("alice", 0o640));
+    assert_eq!(f.state, FileState::Open);
+    assert_eq!(f.xattr("user.tag"), Some("blue"));
+    assert!(back.list_dir("/empty").unwrap().is_empty());
+  }
+
+  #[test]
+  fn links_round_trip() {
+    let mut fs = MemFs::new();
+    fs.create("/a").unwrap();
+    fs.link("/a", "/b").unwrap();
+    fs.symlink("/a", "/s").unwrap();
+    let mut back = round_trip(&fs);
+    assert_eq!(back.file("/b").unwrap().link_count(), 2);
+    assert_eq!(back.read_link("/s"), Ok("/a"));
+    let b = back.file_mut("/b").unwrap();
+    b.open().unwrap();
+    b.write(b"shared").unwrap();
+    assert_eq!(&*back.file("/a").unwrap().contents(), b"shared");
+  }
+
+  #[test]
+  fn encrypted_files_stay_encrypted() {
+    let key = FileKey::generate();
+    let mut fs = MemFs::new();
+    let f = fs.insert("/secret", File::new_with_data("secret", b"plans")).unwrap();
+    f.encrypt(&key).unwrap();
+    let back = round_trip(&fs);
+    let f = back.file("/secret").unwrap();
+    assert!(f.is_encrypted());
+    assert_eq!(f.read_encrypted(&key).unwrap(), b"plans");
+  }
+
+  #[test]
+  fn damaged_data_is_caught() {
+    let mut fs = MemFs::new();
+    fs.insert("/a", File::new_with_data("a", b"some data")).unwrap();
+    let mut tar = write_fs(&fs, Vec::new()).unwrap();
+    let at = tar.windows(9).position(|w| w == b"some data").unwrap();
+    tar[at] ^= 1;
+    assert!(matches!(read_fs(&tar[..]), Err(FileError::Corrupt(_))));
+    assert!(matches!(read_fs(&tar[..BLOCK + 3]), Err(FileError::Corrupt(_))));
+  }
+
+  #[test]
+  fn malformed_pax_records_are_corrupt() {
+    for record in [&b"2 "[..], b"0 \n", b"9 a=b", b"6 a=bc", b"x a=b\n", b"99999999999999999999 a\n"] {
+      assert!(matches!(parse_pax(record), Err(FileError::Corrupt(_))), "{:?}", record);
+    }
+    assert_eq!(parse_pax(b"6 a=b\n").unwrap()["a"], "b");
+  }
+
+  #[test]
+  fn mutated_archives_never_panic() {
+    let mut fs = MemFs::new();
+    fs.mkdir("/d").unwrap();
+    fs.insert("/d/a", File::new_with_data("a", b"contents")).unwrap();
+    fs.link("/d/a", "/b").unwrap();
+    fs.symlink("d/a", "/s").unwrap();
+    let tar = write_fs(&fs, Vec::new()).unwrap();
+    let mut seed = 1u64;
+    for _ in 0..2000 {
+      seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+      let mut bad = tar.clone();
+      let at = (seed >> 33) as usize % bad.len();
+      bad[at] = (seed >> 20) as u8;
+      let _ = read_fs(&bad[..]);
+    }
+  }
+}
@@ -0,0 +1,417 @@
+//! The command line: subcommands that load a store file, act on the `MemFs` in
+//! it, and write it back if they changed anything. The store is a tar archive
+//! as written by `archive::write_fs`, so `tar tvf` can read it too.
+
+use std::env;
+use std::fmt;
+use std::fmt::Display;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::archive::{read_fs, write_fs};
+use crate::format::Table;
+use crate::memfs::{DirEntry, MemFs, NodeKind};
+use crate::persist::write_atomic;
+use crate::time::Rfc3339;
+use crate::{File, FileError};
+
+const DEFAULT_STORE: &str = "memfs.tar";
+
+pub const USAGE: &str = "\
+usage: world_hello [--store PATH] COMMAND [ARGS]
+
+commands:
+  create PATH              create an empty file and any missing parent directories
+  cat PATH                 print a file's contents
+  write [-a] PATH [TEXT]   replace a file's contents with TEXT, or stdin; -a appends
+  ls [-l|-R] [PATH]        list a directory; -l as a table, -R everything below it
+  stat [--json] PATH       show a file's or directory's metadata
+  rm PATH                  remove a file, a symlink or an empty directory
+  ln [-s] TARGET PATH      make PATH a hard link to the file TARGET; -s a symlink
+  import HOST_PATH PATH    copy a file from the host into the store
+  export PATH HOST_PATH    copy a file from the store to the host
+  demo                     run the feature tour
+
+The store is --store, else $MEMFS_STORE, else ./memfs.tar; it is created on first write.";
+
+#[derive(Debug)]
+pub enum CliError {
+  Usage(String),
+  File(FileError),
+}
+
+impl Display for CliError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      CliError::Usage(what) => write!(f, "{}\n\n{}", what, USAGE),
+      CliError::File(e) => write!(f, "{}", e),
+    }
+  }
+}
+
+impl From<FileError> for CliError {
+  fn from(e: FileError) -> CliError {
+    CliError::File(e)
+  }
+}
+
+impl From<io::Error> for CliError {
+  fn from(e: io::Error) -> CliError {
+    CliError::File(e.into())
+  }
+}
+
+impl CliError {
+  /// 2 for bad usage, 1 for anything that went wrong doing the work.
+  pub fn exit_code(&self) -> i32 {
+    match self {
+      CliError::Usage(_) => 2,
+      CliError::File(_) => 1,
+    }
+  }
+}
+
+fn usage(what: &str) -> CliError {
+  CliError::Usage(what.to_string())
+}
+
+/// Takes the leading `-x` flags out of `args`, failing on any not in `known`.
+fn flags(args: &mut Vec<String>, known: &[&str]) -> Result<Vec<String>, CliError> {
+  let mut found = Vec::new();
+  while args.first().is_some_and(|a| a.starts_with('-') && a.len() > 1) {
+    let flag = args.remove(0);
+    if !known.contains(&flag.as_str()) {
+      return Err(usage(&format!("unknown option {}", flag)));
+    }
+    found.push(flag);
+  }
+  Ok(found)
+}
+
+/// Exactly `N` positional arguments.
+fn positional<const N: usize>(args: Vec<String>, command: &str) -> Result<[String; N], CliError> {
+  args.try_into().map_err(|_| usage(&format!("{} takes {} argument(s)", command, N)))
+}
+
+fn load(store: &Path) -> Result<MemFs, CliError> {
+  match fs::File::open(store) {
+    Ok(input) => Ok(read_fs(io::BufReader::new(input))?),
+    Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(MemFs::new()),
+    Err(e) => Err(e.into()),
+  }
+}
+
+fn save(fs: &MemFs, store: &Path) -> Result<(), CliError> {
+  let archive = write_fs(fs, Vec::new())?;
+  write_atomic(store, &archive)?;
+  Ok(())
+}
+
+fn make_parents(fs: &mut MemFs, path: &str) -> Result<(), FileError> {
+  match path.trim_end_matches('/').rsplit_once('/') {
+    Some((parent, _)) if !parent.is_empty() => fs.mkdir_all(parent),
+    _ => Ok(()),
+  }
+}
+
+/// Closes a file that was archived open. Its handle belonged to whatever
+/// saved the store, and each command opens files itself.
+fn close_stale(file: &mut File) -> Result<(), FileError> {
+  if file.state.is_open() {
+    file.close()?;
+  }
+  Ok(())
+}
+
+/// Reads a whole file through the normal open/read/close cycle.
+fn read_file(file: &mut File) -> Result<Vec<u8>, FileError> {
+  close_stale(file)?;
+  file.open_read()?;
+  let mut data = Vec::new();
+  let result = file.read_to_end(&mut data).map_err(FileError::from);
+  file.close()?;
+  result.map(|_| data)
+}
+
+fn write_file(file: &mut File, data: &[u8], append: bool) -> Result<(), FileError> {
+  close_stale(file)?;
+  if append {
+    file.open_append()?;
+  } else {
+    file.open_write()?;
+  }
+  let result = (|| {
+    if !append {
+      file.truncate(0)?;
+    }
+    file.write_all(data).map_err(FileError::from)
+  })();
+  file.close()?;
+  result
+}
+
+/// Describes a symlink itself rather than its target, like `stat` without `-L`.
+fn stat(fs: &MemFs, path: &str, out: &mut impl Write) -> Result<(), CliError> {
+  let meta = fs.symlink_metadata(path)?;
+  writeln!(out, "path: {}", path)?;
+  match meta.kind {
+    NodeKind::Dir => {
+      writeln!(out, "kind: directory")?;
+      writeln!(out, "entries: {}", fs.list_dir(path)?.len())?;
+      writeln!(out, "size: {}", fs.disk_usage(path)?)?;
+    }
+    NodeKind::File => {
+      let file = fs.file(path)?;
+      writeln!(out, "kind: file")?;
+      writeln!(out, "size: {} ({} stored)", file.len(), file.stored_len())?;
+      writeln!(out, "links: {}", meta.links)?;
+      writeln!(out, "state: {}", file.state)?;
+      writeln!(out, "perms: {}", file.perms)?;
+      writeln!(out, "sha256: {}", file.content_hash())?;
+      let times = file.timestamps();
+      writeln!(out, "created: {}", Rfc3339(times.created))?;
+      writeln!(out, "modified: {}", Rfc3339(times.modified))?;
+      writeln!(out, "accessed: {}", Rfc3339(times.accessed))?;
+      for (key, value) in file.xattrs() {
+        writeln!(out, "xattr: {}={}", key, value)?;
+      }
+    }
+    NodeKind::Symlink => {
+      writeln!(out, "kind: symlink")?;
+      writeln!(out, "target: {}", fs.read_link(path)?)?;
+    }
+  }
+  Ok(())
+}
+
+/// Everything below the directory `path` leads to, by absolute path as `walk`
+/// gives them.
+fn list_recursive(fs: &MemFs, path: &str, out: &mut impl Write) -> Result<(), CliError> {
+  fs.metadata(path)?;
+  let real = fs.real_path(path)?;
+  let prefix = format!("{}/", real.trim_end_matches('/'));
+  for (full, entry) in fs.walk() {
+    if full.starts_with(&prefix) {
+      match entry {
+        DirEntry::File(file) => writeln!(out, "{} {}", full, file)?,
+        DirEntry::Dir(_) => writeln!(out, "{}/", full)?,
+        DirEntry::Symlink { target, .. } => writeln!(out, "{} -> {}", full, target)?,
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Runs one command line, without the program name.
+pub fn run(args: &[String]) -> Result<(), CliError> {
+  let mut args = args.to_vec();
+  let mut store = env::var_os("MEMFS_STORE").map(PathBuf::from).unwrap_or_else(|| PathBuf::from(DEFAULT_STORE));
+  if args.first().is_some_and(|a| a == "--store") {
+    args.remove(0);
+    if args.is_empty() {
+      return Err(usage("--store needs a path"));
+    }
+    store = PathBuf::from(args.remove(0));
+  }
+  if args.is_empty() {
+    return Err(usage("no command given"));
+  }
+  let command = args.remove(0);
+  let mut fs = load(&store)?;
+  let stdout = io::stdout();
+  let mut out = stdout.lock();
+
+  match command.as_str() {
+    "create" => {
+      let [path] = positional(args, "create")?;
+      make_parents(&mut fs, &path)?;
+      fs.create(&path)?;
+    }
+    "cat" => {
+      let [path] = positional(args, "cat")?;
+      out.write_all(&read_file(fs.file_mut(&path)?)?)?;
+      return Ok(());
+    }
+    "write" => {
+      let append = !flags(&mut args, &["-a"])?.is_empty();
+      let (path, data) = match args.len() {
+        1 => {
+          let mut data = Vec::new();
+          io::stdin().read_to_end(&mut data)?;
+          (args.remove(0), data)
+        }
+        2 => {
+          let [path, text] = positional(args, "write")?;
+          (path, text.into_bytes())
+        }
+        _ => return Err(usage("write takes a path and optional text")),
+      };
+      if !fs.exists(&path) {
+        make_parents(&mut fs, &path)?;
+        fs.create(&path)?;
+      }
+      write_file(fs.file_mut(&path)?, &data, append)?;
+    }
+    "ls" => {
+      let flags = flags(&mut args, &["-l", "-R"])?;
+      let (long, recursive) = (flags.iter().any(|f| f == "-l"), flags.iter().any(|f| f == "-R"));
+      let path = match args.len() {
+        0 => "/".to_string(),
+        _ => {
+          let [path] = positional(args, "ls")?;
+          path
+        }
+      };
+      if recursive {
+        return list_recursive(&fs, &path, &mut out);
+      } else if long {
+        write!(out, "{}", Table::new(fs.list_dir(&path)?))?;
+      } else {
+        for entry in fs.list_dir(&path)? {
+          writeln!(out, "{}", entry)?;
+        }
+      }
+      return Ok(());
+    }
+    "stat" => {
+      let json = !flags(&mut args, &["--json"])?.is_empty();
+      let [path] = positional(args, "stat")?;
+      if json {
+        writeln!(out, "{}", fs.file(&path)?.json())?;
+        return Ok(());
+      }
+      return stat(&fs, &path, &mut out);
+    }
+    "rm" => {
+      let [path] = positional(args, "rm")?;
+      fs.remove(&path)?;
+    }
+    "ln" => {
+      let symbolic = !flags(&mut args, &["-s"])?.is_empty();
+      let [target, path] = positional(args, "ln")?;
+      make_parents(&mut fs, &path)?;
+      if symbolic {
+        fs.symlink(&target, &path)?;
+      } else {
+        fs.link(&target, &path)?;
+      }
+    }
+    "import" => {
+      let [host, path] = positional(args, "import")?;
+      let mut file = File::load(&host)?;
+      file.backing = None;
+      make_parents(&mut fs, &path)?;
+      fs.insert(&path, file)?;
+    }
+    "export" => {
+      let [path, host] = positional(args, "export")?;
+      let data = read_file(fs.file_mut(&path)?)?;
+      fs::write(host, data)?;
+      return Ok(());
+    }
+    other => return Err(usage(&format!("unknown command {}", other))),
+  }
+  save(&fs, &store)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn store(test: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("memfs-cli-{}-{}.tar", std::process::id(), test));
+    let _ = fs::remove_file(&path);
+    path
+  }
+
+  fn memfs(store: &Path, args: &[&str]) -> Result<(), CliError> {
+    let mut all = vec!["--store".to_string(), store.display().to_string()];
+    all.extend(args.iter().map(|a| a.to_string()));
+    run(&all)
+  }
+
+  fn contents(fs: &mut MemFs, path: &str) -> Vec<u8> {
+    read_file(fs.file_mut(path).unwrap()).unwrap()
+  }
+
+  #[test]
+  fn commands_change_the_store() {
+    let store = store("commands");
+    memfs(&store, &["write", "/docs/a.txt", "hello"]).unwrap();
+    memfs(&store, &["write", "-a", "/docs/a.txt", " world"]).unwrap();
+    memfs(&store, &["create", "/docs/deep/b.txt"]).unwrap();
+    memfs(&store, &["ln", "-s", "a.txt", "/docs/latest"]).unwrap();
+    memfs(&store, &["rm", "/docs/deep/b.txt"]).unwrap();
+    let mut fs = load(&store).unwrap();
+    assert_eq!(contents(&mut fs, "/docs/a.txt"), b"hello world");
+    assert_eq!(contents(&mut fs, "/docs/latest"), b"hello world");
+    assert!(fs.exists("/docs/deep") && !fs.exists("/docs/deep/b.txt"));
+    memfs(&store, &["write", "/docs/a.txt", "new"]).unwrap();
+    assert_eq!(contents(&mut load(&store).unwrap(), "/docs/a.txt"), b"new");
+    fs::remove_file(store).unwrap();
+  }
+
+  #[test]
+  fn files_archived_open_can_be_read_and_written() {
+    let store = store("open");
+    let host = store.with_extension("out");
+    let mut fs = MemFs::new();
+    fs.insert("/a", File::new_with_data("a", b"left open")).unwrap().open().unwrap();
+    save(&fs, &store).unwrap();
+    memfs(&store, &["cat", "/a"]).unwrap();
+    memfs(&store, &["export", "/a", &host.display().to_string()]).unwrap();
+    assert_eq!(fs::read(&host).unwrap(), b"left open");
+    memfs(&store, &["write", "-a", "/a", "!"]).unwrap();
+    assert_eq!(contents(&mut load(&store).unwrap(), "/a"), b"left open!");
+    fs::remove_file(store).unwrap();
+    fs::remove_file(host).unwrap();
+  }
+
+  #[test]
+  fn bad_usage_exits_with_2() {
+    let store = store("usage");
+    for args in [&[][..], &["frobnicate"], &["cat"], &["ls", "-x"], &["ln", "a"]] {
+      let err = memfs(&store, args).unwrap_err();
+      assert_eq!(err.exit_code(), 2, "{:?}", args);
+    }
+    assert_eq!(run(&["--store".to_string()]).unwrap_err().exit_code(), 2);
+    let err = memfs(&store, &["cat", "/missing"]).unwrap_err();
+    assert_eq!((err.exit_code(), err.to_string()), (1, FileError::NotFound.to_string()));
+    assert!(!store.exists());
+  }
+
+  #[test]
+  fn recursive_listing_takes_any_path_form() {
+    let mut fs = MemFs::new();
+    fs.mkdir_all("/d/e").unwrap();
+    fs.create("/d/e/f").unwrap();
+    fs.create("/g").unwrap();
+    fs.symlink("d", "/s").unwrap();
+    let list = |path: &str| {
+      let mut out = Vec::new();
+      list_recursive(&fs, path, &mut out).unwrap();
+      String::from_utf8(out).unwrap().lines().map(|l| l.split(' ').next().unwrap().to_string()).collect::<Vec<_>>()
+    };
+    for path in ["d", "/d", "/d/", "s"] {
+      assert_eq!(list(path), ["/d/e/", "/d/e/f"], "{}", path);
+    }
+    assert_eq!(list("/").len(), 5);
+  }
+
+  #[test]
+  fn stat_describes_each_kind() {
+    let mut fs = MemFs::new();
+    fs.mkdir("/d").unwrap();
+    fs.insert("/d/f", File::new_with_data("f", b"abc")).unwrap();
+    fs.symlink("f", "/d/l").unwrap();
+    let mut out = Vec::new();
+    stat(&fs, "/d/f", &mut out).unwrap();
+    stat(&fs, "/d", &mut out).unwrap();
+    stat(&fs, "/d/l", &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("kind: file\nsize: 3 (3 stored)\nlinks: 1\n"));
+    assert!(out.contains("kind: directory\nentries: 2\nsize: 3\n"));
+    assert!(out.contains("kind: symlink\ntarget: f\n"));
+  }
+}
@@ -0,0 +1,377 @@
+//! An LZ77 codec and a `Storage` backend that keeps file data compressed.
+//!
+//! The stream is a run of sequences in the style of LZ4: a token byte whose high
+//! nibble is the literal count and low nibble the match length minus
+//! `MIN_MATCH` (15 in either means "more length bytes follow"), the literals,
+//! then a little-endian `u16` back-reference offset and any extra match length.
+//! The final sequence has literals only.
+
+use std::fmt;
+use std::fmt::Display;
+use std::ops::Range;
+
+use crate::storage::Storage;
+use crate::FileError;
+
+const MIN_MATCH: usize = 4;
+const MAX_OFFSET: usize = u16::MAX as usize;
+const HASH_BITS: u32 = 14;
+
+/// Uncompressed size of each independently compressed block.
+const BLOCK_SIZE: usize = 64 * 1024;
+
+/// How a `File` keeps its data, set with `File::set_compression`.
+#[derive(Debug,Clone,Copy,PartialEq,Default)]
+pub enum Compression {
+  #[default]
+  None,
+  Lz77,
+}
+
+#[derive(Debug)]
+enum Block {
+  /// Kept as is because compressing didn't make it smaller.
+  Raw(Vec<u8>),
+  Lz77 { packed: Vec<u8>, len: usize },
+}
+
+/// Data split into `BLOCK_SIZE` blocks, each compressed on its own so reads and
+/// overwrites only unpack the blocks they touch.
+#[derive(Default)]
+pub struct Compressed {
+  blocks: Vec<Block>,
+  len: usize,
+}
+
+impl Display for Compression {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Compression::None => write!(f, "none"),
+      Compression::Lz77 => write!(f, "lz77"),
+    }
+  }
+}
+
+fn hash(bytes: &[u8]) -> usize {
+  let v = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+  (v.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+fn push_length(out: &mut Vec<u8>, mut extra: usize) {
+  while extra >= 255 {
+    out.push(255);
+    extra -= 255;
+  }
+  out.push(extra as u8);
+}
+
+fn push_sequence(out: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+  let lit_nibble = literals.len().min(15);
+  let match_extra = matched.map(|(_, len)| len - MIN_MATCH).unwrap_or(0);
+  let match_nibble = match_extra.min(15);
+  out.push(((lit_nibble as u8) << 4) | match_nibble as u8);
+  if lit_nibble == 15 {
+    push_length(out, literals.len() - 15);
+  }
+  out.extend_from_slice(literals);
+  if let Some((offset, _)) = matched {
+    out.extend_from_slice(&(offset as u16).to_le_bytes());
+    if match_nibble == 15 {
+      push_length(out, match_extra - 15);
+    }
+  }
+}
+
+pub fn compress(input: &[u8]) -> Vec<u8> {
+  let mut out = Vec::with_capacity(input.len() / 2 + 16);
+  let mut table = vec![usize::MAX; 1 << HASH_BITS];
+  let mut anchor = 0;
+  let mut i = 0;
+  while i + MIN_MATCH <= input.len() {
+    let h = hash(&input[i..]);
+    let candidate = table[h];
+    table[h] = i;
+    if candidate != usize::MAX
+      && i - candidate <= MAX_OFFSET
+      && input[candidate..candidate + MIN_MATCH] == input[i..i + MIN_MATCH]
+    {
+      let mut len = MIN_MATCH;
+      while i + len < input.len() && input[candidate + len] == input[i + len] {
+        len += 1;
+      }
+      push_sequence(&mut out, &input[anchor..i], Some((i - candidate, len)));
+      i += len;
+      anchor = i;
+    } else {
+      i += 1;
+    }
+  }
+  push_sequence(&mut out, &input[anchor..], None);
+  out
+}
+
+fn read_length(input: &[u8], pos: &mut usize, nibble: usize) -> Result<usize, FileError> {
+  let mut len = nibble;
+  if nibble == 15 {
+    loop {
+      let byte = *input.get(*pos).ok_or_else(|| corrupt("truncated length"))?;
+      *pos += 1;
+      len += byte as usize;
+      if byte != 255 {
+        break;
+      }
+    }
+  }
+  Ok(len)
+}
+
+fn corrupt(what: &str) -> FileError {
+  FileError::Corrupt(format!("lz77: {}", what))
+}
+
+/// Unpacks `input`, which must decode to exactly `len` bytes.
+pub fn decompress(input: &[u8], len: usize) -> Result<Vec<u8>, FileError> {
+  let mut out = Vec::with_capacity(len);
+  let mut pos = 0;
+  while pos < input.len() {
+    let token = input[pos] as usize;
+    pos += 1;
+    let lit_len = read_length(input, &mut pos, token >> 4)?;
+    let literals = input.get(pos..pos + lit_len).ok_or_else(|| corrupt("truncated literals"))?;
+    out.extend_from_slice(literals);
+    pos += lit_len;
+    if pos == input.len() {
+      break;
+    }
+
+    let offset = input.get(pos..pos + 2).ok_or_else(|| corrupt("truncated offset"))?;
+    let offset = u16::from_le_bytes([offset[0], offset[1]]) as usize;
+    pos += 2;
+    let match_len = read_length(input, &mut pos, token & 0xf)? + MIN_MATCH;
+    if offset == 0 || offset > out.len() || out.len() + match_len > len {
+      return Err(corrupt("bad back-reference"));
+    }
+    let start = out.len() - offset;
+    for k in 0..match_len {
+      out.push(out[start + k]);
+    }
+  }
+  if out.len() != len {
+    return Err(corrupt("length mismatch"));
+  }
+  Ok(out)
+}
+
+impl Block {
+  /// Compresses `data` if that makes it smaller. The packed form is unpacked
+  /// again before it is kept, so a stored block never fails to unpack.
+  fn pack(data: Vec<u8>) -> Block {
+    let packed = compress(&data);
+    if packed.len() < data.len() && decompress(&packed, data.len()).is_ok_and(|check| check == data) {
+      Block::Lz77 { packed, len: data.len() }
+    } else {
+      Block::Raw(data)
+    }
+  }
+
+  fn unpack(&self) -> Result<Vec<u8>, FileError> {
+    match self {
+      Block::Raw(data) => Ok(data.clone()),
+      Block::Lz77 { packed, len } => decompress(packed, *len),
+    }
+  }
+
+  fn len(&self) -> usize {
+    match self {
+      Block::Raw(data) => data.len(),
+      Block::Lz77 { len, .. } => *len,
+    }
+  }
+
+  fn stored_len(&self) -> usize {
+    match self {
+      Block::Raw(data) => data.len(),
+      Block::Lz77 { packed, .. } => packed.len(),
+    }
+  }
+}
+
+impl fmt::Debug for Compressed {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("Compressed")
+      .field("len", &self.len)
+      .field("stored_len", &self.stored_len())
+      .field("blocks", &self.blocks.len())
+      .finish()
+  }
+}
+
+impl Compressed {
+  pub fn from_bytes(data: &[u8]) -> Compressed {
+    Compressed {
+      blocks: data.chunks(BLOCK_SIZE).map(|c| Block::pack(c.to_vec())).collect(),
+      len: data.len(),
+    }
+  }
+
+  fn to_vec(&self) -> Result<Vec<u8>, FileError> {
+    let mut out = Vec::with_capacity(self.len);
+    for block in &self.blocks {
+      out.extend(block.unpack()?);
+    }
+    Ok(out)
+  }
+
+  /// Unpacks everything, applies `edit` and repacks; used by edits that shift data.
+  fn rewrite(&mut self, edit: impl FnOnce(&mut Vec<u8>) -> Result<(), FileError>) -> Result<(), FileError> {
+    let mut data = self.to_vec()?;
+    edit(&mut data)?;
+    *self = Compressed::from_bytes(&data);
+    Ok(())
+  }
+
+  fn block_range(&self, range: Range<usize>) -> Range<usize> {
+    range.start / BLOCK_SIZE..range.end.div_ceil(BLOCK_SIZE).min(self.blocks.len())
+  }
+}
+
+impl Storage for Compressed {
+  fn len(&self) -> usize {
+    self.len
+  }
+
+  /// Blocks are checked by `pack`, so one failing to unpack here is a bug, and
+  /// panics rather than passing for a short file.
+  fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+    let end = offset.saturating_add(buf.len()).min(self.len);
+    if offset >= end {
+      return 0;
+    }
+    let mut n = 0;
+    for b in self.block_range(offset..end) {
+      let block = self.blocks[b].unpack().expect("packed blocks are checked when made");
+      let base = b * BLOCK_SIZE;
+      let from = (offset + n) - base;
+      let take = (block.len() - from).min(end - offset - n);
+      buf[n..n + take].copy_from_slice(&block[from..from + take]);
+      n += take;
+    }
+    n
+  }
+
+  fn write_at(&mut self, offset: usize, buf: &[u8]) -> Result<(), FileError> {
+    let end = offset.checked_add(buf.len()).ok_or(FileError::OutOfSpace)?;
+    if end > self.len {
+      self.resize(end)?;
+    }
+    for b in self.block_range(offset..end) {
+      let mut block = self.blocks[b].unpack()?;
+      let base = b * BLOCK_SIZE;
+      let from = offset.max(base);
+      let to = end.min(base + block.len());
+      block[from - base..to - base].copy_from_slice(&buf[from - offset..to - offset]);
+      self.blocks[b] = Block::pack(block);
+    }
+    Ok(())
+  }
+
+  fn insert(&mut self, offset: usize, buf: &[u8]) -> Result<(), FileError> {
+    if offset > self.len {
+      return Err(FileError::InvalidSeek);
+    }
+    self.rewrite(|data| {
+      data.splice(offset..offset, buf.iter().copied());
+      Ok(())
+    })
+  }
+
+  fn remove(&mut self, range: Range<usize>) -> Result<(), FileError> {
+    if range.start > range.end || range.end > self.len {
+      return Err(FileError::InvalidSeek);
+    }
+    self.rewrite(|data| {
+      data.drain(range);
+      Ok(())
+    })
+  }
+
+  fn resize(&mut self, len: usize) -> Result<(), FileError> {
+    if len <= self.len {
+      return self.rewrite(|data| {
+        data.truncate(len);
+        Ok(())
+      });
+    }
+    // Top up the last partial block, then append whole blocks of zeros.
+    let mut missing = len - self.len;
+    if let Some(last) = self.blocks.last_mut().filter(|b| b.len() < BLOCK_SIZE) {
+      let mut block = last.unpack()?;
+      let grow = missing.min(BLOCK_SIZE - block.len());
+      block.resize(block.len() + grow, 0);
+      *last = Block::pack(block);
+      missing -= grow;
+    }
+    while missing > 0 {
+      let size = missing.min(BLOCK_SIZE);
+      self.blocks.push(Block::pack(vec![0; size]));
+      missing -= size;
+    }
+    self.len = len;
+    Ok(())
+  }
+
+  fn stored_len(&self) -> usize {
+    self.blocks.iter().map(Block::stored_len).sum()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::storage::tests::matches_vec;
+  use crate::File;
+
+  #[test]
+  fn compress_round_trips() {
+    let text: Vec<u8> = b"the quick brown fox ".iter().cycle().take(10_000).copied().collect();
+    let noise: Vec<u8> = (0..5000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect();
+    for data in [&b""[..], b"abc", &text, &noise] {
+      assert_eq!(decompress(&compress(data), data.len()).unwrap(), data);
+    }
+    assert!(compress(&text).len() < text.len() / 10);
+  }
+
+  #[test]
+  fn truncated_input_is_corrupt() {
+    let data = vec![b'z'; 1000];
+    let packed = compress(&data);
+    assert!(matches!(decompress(&packed[..packed.len() / 2], data.len()), Err(FileError::Corrupt(_))));
+    assert!(decompress(&packed, data.len() + 1).is_err());
+  }
+
+  #[test]
+  #[should_panic(expected = "checked when made")]
+  fn damaged_block_is_not_a_short_read() {
+    let mut data = Compressed::from_bytes(&vec![b'z'; 1000]);
+    let Block::Lz77 { packed, .. } = &mut data.blocks[0] else { panic!("not packed") };
+    packed.truncate(packed.len() / 2);
+    data.read_at(0, &mut [0; 1000]);
+  }
+
+  #[test]
+  fn compressed_behaves_like_vec() {
+    matches_vec(Box::new(Compressed::from_bytes(&vec![3u8; BLOCK_SIZE * 2 + 17])), 100);
+  }
+
+  #[test]
+  fn set_compression_is_transparent() {
+    let mut f = File::new_with_data("a", &vec![b'a'; 100_000]);
+    f.set_compression(Compression::Lz77).unwrap();
+    assert!(f.stored_len() < 1000);
+    f.open().unwrap();
+    f.write(b"b").unwrap();
+    f.set_compression(Compression::None).unwrap();
+    assert_eq!(f.stored_len(), 100_000);
+    assert_eq!(&f.contents()[..2], b"ba");
+  }
+}
@@ -0,0 +1,310 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+use crate::compress::Compression;
+use crate::journal::Op;
+use crate::perm::Access;
+use crate::persist::write_atomic;
+use crate::state::{FileEvent, FileState};
+use crate::{File, FileError};
+
+/// Starts sealed data; `open` refuses anything else.
+const MAGIC: &[u8; 5] = b"MFSE\x01";
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// A 256-bit ChaCha20-Poly1305 key. `Debug` never prints the key bytes.
+#[derive(Clone)]
+pub struct FileKey(Key);
+
+impl FileKey {
+  pub fn generate() -> FileKey {
+    FileKey(ChaCha20Poly1305::generate_key(&mut OsRng))
+  }
+
+  pub fn from_bytes(bytes: [u8; 32]) -> FileKey {
+    FileKey(Key::from(bytes))
+  }
+
+  fn cipher(&self) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(&self.0)
+  }
+}
+
+impl fmt::Debug for FileKey {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "FileKey(..)")
+  }
+}
+
+/// Encrypts under a fresh random nonce; the result is `MAGIC || nonce || ciphertext || tag`.
+fn seal(key: &FileKey, plaintext: &[u8]) -> Result<Vec<u8>, FileError> {
+  let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+  let ciphertext = key.cipher().encrypt(&nonce, plaintext).map_err(|_| FileError::EncryptionFailed)?;
+  let mut sealed = MAGIC.to_vec();
+  sealed.extend(nonce);
+  sealed.extend(ciphertext);
+  Ok(sealed)
+}
+
+/// Fails with `Tampered` if the data was modified or `key` is not the one it was sealed with.
+fn open(key: &FileKey, sealed: &[u8]) -> Result<Vec<u8>, FileError> {
+  let Some(sealed) = sealed.strip_prefix(MAGIC.as_slice()).filter(|s| s.len() >= NONCE_LEN) else {
+    return Err(FileError::Tampered);
+  };
+  let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+  key.cipher().decrypt(Nonce::from_slice(nonce), ciphertext).map_err(|_| FileError::Tampered)
+}
+
+/// The marker next to the backing file `path` that says it holds sealed data.
+/// Plain data never has one, whatever bytes it starts with.
+pub fn sealed_marker_path(path: &Path) -> PathBuf {
+  let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+  path.with_file_name(format!(".{}.sealed", name))
+}
+
+/// Whether the backing file `path` was written sealed.
+pub(crate) fn load_sealed_flag(path: &Path) -> Result<bool, FileError> {
+  Ok(sealed_marker_path(path).try_exists()?)
+}
+
+/// Records whether the backing file `path` holds sealed data.
+pub(crate) fn store_sealed_flag(path: &Path, encrypted: bool) -> Result<(), FileError> {
+  let marker = sealed_marker_path(path);
+  if encrypted {
+    return write_atomic(&marker, b"");
+  }
+  match fs::remove_file(&marker) {
+    Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+    _ => Ok(()),
+  }
+}
+
+impl File {
+  pub fn is_encrypted(&self) -> bool {
+    self.encrypted
+  }
+
+  /// Replaces the data with its ciphertext. From then on plain reads and writes
+  /// are refused; use `read_encrypted`/`write_encrypted` with the same key.
+  /// Fails with `HasSnapshots` until earlier versions are cleared.
+  pub fn encrypt(&mut self, key: &FileKey) -> Result<(), FileError> {
+    self.ensure_sealable()?;
+    self.ensure_plaintext()?;
+    let sealed = seal(key, &self.data.contents())?;
+    self.reserved(sealed.len(), |file| file.log(Op::Seal(&sealed)))?;
+    self.unshare_storage(Box::new(sealed));
+    self.compression = Compression::None;
+    self.encrypted = true;
+    let len = self.data.len();
+    self.modified(0, len);
+    Ok(())
+  }
+
+  /// Turns an encrypted file back into a plain one.
+  pub fn decrypt(&mut self, key: &FileKey) -> Result<(), FileError> {
+    self.ensure_sealable()?;
+    let plaintext = self.read_encrypted(key)?;
+    self.log(Op::Replace(&plaintext))?;
+    self.unshare_storage(Box::new(plaintext));
+    self.encrypted = false;
+    let len = self.data.len();
+    self.modified(0, len);
+    Ok(())
+  }
+
+  /// Decrypts and returns the whole data, checking that it was not tampered with.
+  pub fn read_encrypted(&self, key: &FileKey) -> Result<Vec<u8>, FileError> {
+    self.ensure_encrypted()?;
+    self.check_access(Access::Read)?;
+    open(key, &self.data.contents())
+  }
+
+  /// Replaces the whole data, sealing it again under a fresh nonce. Like
+  /// `write`, needs the file open for writing.
+  pub fn write_encrypted(&mut self, key: &FileKey, plaintext: &[u8]) -> Result<(), FileError> {
+    self.transition(FileEvent::Write)?;
+    self.check_access(Access::Write)?;
+    self.ensure_encrypted()?;
+    // Refuse to overwrite data sealed under another key.
+    open(key, &self.data.contents())?;
+    self.inject_write_fault()?;
+    let sealed = seal(key, plaintext)?;
+    let old_len = self.data.len();
+    self.reserved(sealed.len(), |file| file.log(Op::Seal(&sealed)))?;
+    self.set_storage(Box::new(sealed));
+    let len = self.data.len();
+    self.modified(0, len.max(old_len));
+    Ok(())
+  }
+
+  fn ensure_encrypted(&self) -> Result<(), FileError> {
+    if self.state == FileState::Deleted {
+      return Err(FileError::NotFound);
+    }
+    if !self.encrypted {
+      return Err(FileError::NotEncrypted);
+    }
+    Ok(())
+  }
+
+  /// Switching modes rewrites the data wholesale, so no handle may be open,
+  /// and it needs the same permission as any other write.
+  fn ensure_sealable(&self) -> Result<(), FileError> {
+    // `encrypted` is per link, so sealing must not change the data under other names.
+    self.ensure_unlinked()?;
+    // Old versions would keep the plaintext around, or come back as garbage.
+    if !self.versions().is_empty() {
+      return Err(FileError::HasSnapshots);
+    }
+    match self.state {
+      FileState::Closed | FileState::Locked => {}
+      FileState::Deleted => return Err(FileError::NotFound),
+      _ => return Err(FileError::AlreadyOpen),
+    }
+    self.check_access(Access::Write)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fault::FaultPlan;
+  use crate::perm::User;
+  use crate::watch::WatchEvent;
+
+  #[test]
+  fn encrypt_and_decrypt_round_trip() {
+    let key = FileKey::generate();
+    let mut f = File::new_with_data("a", b"top secret");
+    f.encrypt(&key).unwrap();
+    assert!(f.contents().starts_with(MAGIC));
+    assert!(!f.contents().windows(6).any(|w| w == b"secret"));
+    f.open().unwrap();
+    assert_eq!(f.read(&mut [0u8; 4]), Err(FileError::Encrypted));
+    f.write_encrypted(&key, b"new secret").unwrap();
+    f.close().unwrap();
+    f.decrypt(&key).unwrap();
+    assert_eq!(&*f.contents(), b"new secret");
+  }
+
+  #[test]
+  fn wrong_key_or_tampering_is_detected() {
+    let key = FileKey::generate();
+    let mut f = File::new_with_data("a", b"data");
+    f.encrypt(&key).unwrap();
+    f.open().unwrap();
+    assert_eq!(f.read_encrypted(&FileKey::from_bytes([9; 32])), Err(FileError::Tampered));
+    assert_eq!(f.write_encrypted(&FileKey::from_bytes([9; 32]), b"x"), Err(FileError::Tampered));
+    let last = f.len() - 1;
+    let flipped = [f.contents()[last] ^ 1];
+    f.data.write_at(last, &flipped).unwrap();
+    assert_eq!(f.read_encrypted(&key), Err(FileError::Tampered));
+  }
+
+  #[test]
+  fn write_encrypted_is_checked_like_write() {
+    let key = FileKey::generate();
+    let mut f = File::new_with_data("a", b"data");
+    f.encrypt(&key).unwrap();
+    assert_eq!(f.write_encrypted(&key, b"x"), Err(FileError::NotOpen));
+    f.open().unwrap();
+    f.set_faults(Some(FaultPlan { fail_nth_write: Some(1), ..FaultPlan::default() }));
+    assert_eq!(f.write_encrypted(&key, b"x"), Err(FileError::InjectedFault));
+    assert_eq!(f.read_encrypted(&key).unwrap(), b"data");
+    f.set_faults(None);
+    let (_, rx) = f.watch();
+    f.write_encrypted(&key, b"longer data").unwrap();
+    let stored = f.len();
+    let events: Vec<WatchEvent> = rx.try_iter().map(|n| n.event).collect();
+    assert_eq!(events, [WatchEvent::Written { offset: 0, len: stored }]);
+  }
+
+  #[test]
+  fn switching_modes_needs_write_permission() {
+    let key = FileKey::generate();
+    let mut f = File::new_with_data("a", b"data");
+    f.chown("alice", "staff").unwrap();
+    f.chmod(0o644).unwrap();
+    f.set_user(User::new("bob", &[]));
+    assert_eq!(f.encrypt(&key), Err(FileError::PermissionDenied));
+    assert!(!f.is_encrypted());
+  }
+
+  #[test]
+  fn refuses_snapshots_and_links() {
+    let key = FileKey::generate();
+    let mut f = File::new_with_data("a", b"data");
+    f.snapshot("plain");
+    assert_eq!(f.encrypt(&key), Err(FileError::HasSnapshots));
+    f.clear_snapshots();
+    let _link = f.hard_link("b").unwrap();
+    assert_eq!(f.encrypt(&key), Err(FileError::HardLinked));
+  }
+
+  /// An empty directory of its own for each test.
+  fn scratch(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("memfs-crypt-{}-{}", std::process::id(), test));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  #[test]
+  fn loading_sealed_data_marks_it_encrypted() {
+    let dir = scratch("load");
+    let path = dir.join("a.bin");
+    let key = FileKey::generate();
+    let mut f = File::new_with_data("a", b"data");
+    f.encrypt(&key).unwrap();
+    f.sync_to(&path).unwrap();
+    let loaded = File::load(&path).unwrap();
+    assert!(loaded.is_encrypted());
+    assert_eq!(loaded.read_encrypted(&key).unwrap(), b"data");
+    f.decrypt(&key).unwrap();
+    f.flush().unwrap();
+    assert!(!File::load(&path).unwrap().is_encrypted());
+    assert!(!sealed_marker_path(&path).exists());
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn plain_data_that_looks_sealed_stays_plain() {
+    let dir = scratch("lookalike");
+    let path = dir.join("a.bin");
+    let mut data = MAGIC.to_vec();
+    data.extend([0; NONCE_LEN + TAG_LEN + 8]);
+    fs::write(&path, &data).unwrap();
+    let mut loaded = File::load(&path).unwrap();
+    assert!(!loaded.is_encrypted());
+    loaded.open().unwrap();
+    let mut buf = vec![0; data.len()];
+    assert_eq!(loaded.read(&mut buf), Ok(data.len()));
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn recover_replays_the_encrypted_flag() {
+    let dir = scratch("recover");
+    let path = dir.join("a.bin");
+    let key = FileKey::generate();
+    let mut f = File::new_with_data("a", b"data");
+    f.sync_to(&path).unwrap();
+    f.enable_journal().unwrap();
+    f.encrypt(&key).unwrap();
+    drop(f);
+    let mut f = File::recover(&path).unwrap();
+    assert!(f.is_encrypted());
+    f.decrypt(&key).unwrap();
+    drop(f);
+    let f = File::recover(&path).unwrap();
+    assert!(!f.is_encrypted());
+    assert_eq!(&*f.contents(), b"data");
+    fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -0,0 +1,195 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::Display;
+use std::io;
+
+use crate::state::{FileEvent, FileState};
+
+/// Every way a `File` or `MemFs` operation can fail.
+#[derive(Debug,Clone,PartialEq)]
+pub enum FileError {
+  AlreadyOpen,
+  NotOpen,
+  InvalidSeek,
+  NotFound,
+  AlreadyExists,
+  NotADirectory,
+  IsADirectory,
+  DirectoryNotEmpty,
+  InvalidPath,
+  PermissionDenied,
+  OutOfSpace,
+  Locked,
+  /// Waiting for the lock would complete a cycle of threads waiting on each other.
+  Deadlock,
+  TimedOut,
+  NoSuchVersion(usize),
+  /// Stored or serialized data failed validation.
+  Corrupt(String),
+  /// Plain reads and writes are refused on an encrypted file.
+  Encrypted,
+  NotEncrypted,
+  /// A typed read of `len` bytes at `offset` runs past the `size`-byte view.
+  OutOfBounds { offset: usize, len: usize, size: usize },
+  /// The storage backend can't lend its data as one slice.
+  NotContiguous,
+  /// Authenticated decryption failed: the ciphertext was altered or the key is wrong.
+  Tampered,
+  /// The cipher refused to seal the data, which only happens past its length
+  /// limit of about 256 GiB.
+  EncryptionFailed,
+  /// Line `line` (1-based) has invalid UTF-8 starting at byte `offset` of the data.
+  InvalidUtf8 { line: usize, offset: usize },
+  /// An extended attribute key that is empty, holds `=` or is reserved.
+  InvalidXattr(String),
+  /// Resolving a path went through more symlinks than `memfs::MAX_SYMLINK_HOPS`,
+  /// most likely because they form a loop.
+  SymlinkLoop,
+  /// The change would apply to data other hard links share; see `link`.
+  HardLinked,
+  /// Encrypting or decrypting would leave snapshots holding the data in the
+  /// other form; clear them first.
+  HasSnapshots,
+  /// `seek_data` or `seek_hole` found nothing at or after `offset`.
+  NoData { offset: u64 },
+  /// A write refused on purpose by a `fault::FaultPlan`.
+  InjectedFault,
+  /// `event` is not allowed while the file is in state `from`.
+  IllegalTransition { from: FileState, event: FileEvent },
+  /// An underlying `std::io` error with no closer match.
+  Io(io::ErrorKind),
+}
+
+impl FileError {
+  /// The closest `std::io::ErrorKind`, used when surfacing through `Read`/`Write`/`Seek`.
+  pub fn kind(&self) -> io::ErrorKind {
+    match self {
+      FileError::AlreadyOpen | FileError::NotOpen | FileError::IllegalTransition { .. } | FileError::InjectedFault => {
+        io::ErrorKind::Other
+      }
+      FileError::Locked => io::ErrorKind::ResourceBusy,
+      FileError::Deadlock => io::ErrorKind::Deadlock,
+      FileError::TimedOut => io::ErrorKind::TimedOut,
+      FileError::InvalidSeek | FileError::InvalidPath | FileError::InvalidXattr(_) | FileError::EncryptionFailed => {
+        io::ErrorKind::InvalidInput
+      }
+      FileError::Corrupt(_) | FileError::Tampered | FileError::InvalidUtf8 { .. } => io::ErrorKind::InvalidData,
+      FileError::Encrypted | FileError::NotEncrypted | FileError::NotContiguous | FileError::HardLinked
+      | FileError::HasSnapshots => {
+        io::ErrorKind::Unsupported
+      }
+      // `FilesystemLoop`, the kind for ELOOP, is not stable yet.
+      FileError::SymlinkLoop => io::ErrorKind::Other,
+      FileError::OutOfBounds { .. } | FileError::NoData { .. } => io::ErrorKind::UnexpectedEof,
+      FileError::NotFound | FileError::NoSuchVersion(_) => io::ErrorKind::NotFound,
+      FileError::AlreadyExists => io::ErrorKind::AlreadyExists,
+      FileError::NotADirectory => io::ErrorKind::NotADirectory,
+      FileError::IsADirectory => io::ErrorKind::IsADirectory,
+      FileError::DirectoryNotEmpty => io::ErrorKind::DirectoryNotEmpty,
+      FileError::PermissionDenied => io::ErrorKind::PermissionDenied,
+      FileError::OutOfSpace => io::ErrorKind::StorageFull,
+      FileError::Io(kind) => *kind,
+    }
+  }
+}
+
+impl Display for FileError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      FileError::AlreadyOpen => write!(f, "file is already open"),
+      FileError::NotOpen => write!(f, "file is not open"),
+      FileError::InvalidSeek => write!(f, "seek to a negative or overflowing position"),
+      FileError::NotFound => write!(f, "no such file or directory"),
+      FileError::AlreadyExists => write!(f, "file already exists"),
+      FileError::NotADirectory => write!(f, "not a directory"),
+      FileError::IsADirectory => write!(f, "is a directory"),
+      FileError::DirectoryNotEmpty => write!(f, "directory not empty"),
+      FileError::InvalidPath => write!(f, "invalid path"),
+      FileError::PermissionDenied => write!(f, "permission denied"),
+      FileError::OutOfSpace => write!(f, "no space left for file data"),
+      FileError::Locked => write!(f, "file is locked"),
+      FileError::Deadlock => write!(f, "lock request would deadlock"),
+      FileError::TimedOut => write!(f, "timed out waiting for lock"),
+      FileError::NoSuchVersion(id) => write!(f, "no such version: v{}", id),
+      FileError::Corrupt(what) => write!(f, "corrupt data: {}", what),
+      FileError::Encrypted => write!(f, "file is encrypted"),
+      FileError::NotEncrypted => write!(f, "file is not encrypted"),
+      FileError::OutOfBounds { offset, len, size } => {
+        write!(f, "{} bytes at offset {} is out of bounds for {} bytes", len, offset, size)
+      }
+      FileError::NotContiguous => write!(f, "file data is not contiguous"),
+      FileError::Tampered => write!(f, "decryption failed: data tampered with or wrong key"),
+      FileError::EncryptionFailed => write!(f, "encryption failed: data too large for the cipher"),
+      FileError::InvalidXattr(key) => write!(f, "invalid extended attribute name: {:?}", key),
+      FileError::SymlinkLoop => write!(f, "too many levels of symbolic links"),
+      FileError::HardLinked => write!(f, "file data is shared with other hard links"),
+      FileError::HasSnapshots => write!(f, "file has snapshots of its data"),
+      FileError::NoData { offset } => write!(f, "no data at or after offset {}", offset),
+      FileError::InjectedFault => write!(f, "injected write failure"),
+      FileError::InvalidUtf8 { line, offset } => {
+        write!(f, "invalid UTF-8 on line {} at byte {}", line, offset)
+      }
+      FileError::IllegalTransition { from, event } => {
+        write!(f, "cannot {} a file in state {}", event, from)
+      }
+      FileError::Io(kind) => write!(f, "i/o error: {}", kind),
+    }
+  }
+}
+
+impl Error for FileError {}
+
+impl From<FileError> for io::Error {
+  fn from(err: FileError) -> io::Error {
+    io::Error::new(err.kind(), err)
+  }
+}
+
+impl From<io::Error> for FileError {
+  fn from(err: io::Error) -> FileError {
+    if let Some(inner) = err.get_ref().and_then(|e| e.downcast_ref::<FileError>()) {
+      return inner.clone();
+    }
+    match err.kind() {
+      io::ErrorKind::NotFound => FileError::NotFound,
+      io::ErrorKind::AlreadyExists => FileError::AlreadyExists,
+      io::ErrorKind::PermissionDenied => FileError::PermissionDenied,
+      io::ErrorKind::NotADirectory => FileError::NotADirectory,
+      io::ErrorKind::IsADirectory => FileError::IsADirectory,
+      io::ErrorKind::DirectoryNotEmpty => FileError::DirectoryNotEmpty,
+      io::ErrorKind::StorageFull => FileError::OutOfSpace,
+      io::ErrorKind::ResourceBusy => FileError::Locked,
+      io::ErrorKind::Deadlock => FileError::Deadlock,
+      io::ErrorKind::TimedOut => FileError::TimedOut,
+      io::ErrorKind::InvalidData => FileError::Corrupt(err.to_string()),
+      kind => FileError::Io(kind),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn survives_a_trip_through_io_error() {
+    let err = FileError::NoSuchVersion(3);
+    let io_err = io::Error::from(err.clone());
+    assert_eq!(io_err.kind(), io::ErrorKind::NotFound);
+    assert_eq!(FileError::from(io_err), err);
+  }
+
+  #[test]
+  fn plain_io_errors_map_to_the_closest_variant() {
+    assert_eq!(FileError::from(io::Error::from(io::ErrorKind::PermissionDenied)), FileError::PermissionDenied);
+    assert_eq!(FileError::from(io::Error::from(io::ErrorKind::StorageFull)), FileError::OutOfSpace);
+    assert_eq!(FileError::from(io::Error::from(io::ErrorKind::BrokenPipe)), FileError::Io(io::ErrorKind::BrokenPipe));
+  }
+
+  #[test]
+  fn messages() {
+    assert_eq!(FileError::NotOpen.to_string(), "file is not open");
+    let err = FileError::OutOfBounds { offset: 4, len: 8, size: 10 };
+    assert_eq!(err.to_string(), "8 bytes at offset 4 is out of bounds for 10 bytes");
+  }
+}
@@ -0,0 +1,333 @@
+//! Fault injection: making a `File`, or every file in a `MemFs`, fail writes,
+//! return short reads, flip bits in what it reads, or stall, to exercise I/O
+//! error paths in code under test.
+//!
+//! Faults follow a seeded schedule, so the same `FaultPlan` and the same calls
+//! give the same faults every run. A `MemFs` gives each file its own schedule,
+//! derived from the seed and the file's path. Writes count every call that
+//! changes the data: `write`, `insert_at`, `remove_range` and `truncate`.
+//!
+//! Delays sleep the calling thread, except under the tokio traits, where the
+//! poll returns `Pending` until the delay is over.
+
+use std::mem;
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::archive::crc32;
+use crate::memfs::MemFs;
+use crate::{File, FileError};
+
+/// What to inject and how often. Rates are chances per call, from 0 to 1.
+#[derive(Debug,Clone,PartialEq)]
+pub struct FaultPlan {
+  pub seed: u64,
+  /// Fail the `n`th write, counting from 1.
+  pub fail_nth_write: Option<u64>,
+  pub write_failure_rate: f64,
+  /// Reads return at least one byte but fewer than they could.
+  pub short_read_rate: f64,
+  /// Reads come back with one bit flipped.
+  pub corrupt_rate: f64,
+  /// Reads and writes wait for `delay` first.
+  pub delay_rate: f64,
+  pub delay: Duration,
+  /// What a failed write returns.
+  pub error: FileError,
+}
+
+/// One injected fault, as recorded in `Faults::log`.
+#[derive(Debug,Clone,PartialEq)]
+pub enum Fault {
+  /// Write number `write`, counting from 1, was refused.
+  WriteFailed { write: u64 },
+  ShortRead { wanted: usize, given: usize },
+  /// The byte at `offset` in the file was read with `bit` flipped.
+  Corrupted { offset: usize, bit: u8 },
+  Delayed(Duration),
+}
+
+/// A file's fault schedule and what it has injected so far.
+#[derive(Debug)]
+pub struct Faults {
+  plan: FaultPlan,
+  rng: Rng,
+  writes: u64,
+  log: Vec<Fault>,
+  /// When a delay an async poll is waiting out ends.
+  waiting: Option<Instant>,
+  /// Set while an async poll runs a call whose delay it already drew.
+  drawn: bool,
+}
+
+/// SplitMix64: small, fast and the same everywhere.
+#[derive(Debug,Clone)]
+struct Rng(u64);
+
+impl Default for FaultPlan {
+  /// Injects nothing until rates or `fail_nth_write` are set.
+  fn default() -> FaultPlan {
+    FaultPlan {
+      seed: 0,
+      fail_nth_write: None,
+      write_failure_rate: 0.0,
+      short_read_rate: 0.0,
+      corrupt_rate: 0.0,
+      delay_rate: 0.0,
+      delay: Duration::from_millis(10),
+      error: FileError::InjectedFault,
+    }
+  }
+}
+
+impl FaultPlan {
+  /// The same plan with a seed of its own for the file at `path`.
+  pub fn for_path(&self, path: &str) -> FaultPlan {
+    let mut rng = Rng(self.seed ^ crc32(path.as_bytes()) as u64);
+    FaultPlan { seed: rng.next(), ..self.clone() }
+  }
+}
+
+impl Rng {
+  fn next(&mut self) -> u64 {
+    self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = self.0;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+  }
+
+  /// True with probability `rate`. Draws even when `rate` is 0, and callers
+  /// make every draw a fault could need whether or not it fires, so changing
+  /// one rate doesn't shift the rest of the schedule.
+  fn chance(&mut self, rate: f64) -> bool {
+    let draw = (self.next() >> 11) as f64 / (1u64 << 53) as f64;
+    draw < rate
+  }
+
+  /// A number in `0..n`, or 0 if `n` is 0; draws either way.
+  fn below(&mut self, n: usize) -> usize {
+    (self.next() % n.max(1) as u64) as usize
+  }
+}
+
+impl Faults {
+  pub fn new(plan: FaultPlan) -> Faults {
+    Faults { rng: Rng(plan.seed), plan, writes: 0, log: Vec::new(), waiting: None, drawn: false }
+  }
+
+  pub fn plan(&self) -> &FaultPlan {
+    &self.plan
+  }
+
+  /// Every fault injected so far, oldest first.
+  pub fn log(&self) -> &[Fault] {
+    &self.log
+  }
+
+  /// Writes attempted so far, failed ones included.
+  pub fn writes(&self) -> u64 {
+    self.writes
+  }
+
+  fn draw_delay(&mut self) -> Option<Duration> {
+    if !self.rng.chance(self.plan.delay_rate) {
+      return None;
+    }
+    self.log.push(Fault::Delayed(self.plan.delay));
+    Some(self.plan.delay)
+  }
+
+  fn maybe_delay(&mut self) {
+    if mem::take(&mut self.drawn) {
+      return;
+    }
+    if let Some(delay) = self.draw_delay() {
+      thread::sleep(delay);
+    }
+  }
+}
+
+/// Wakes `waker` at `until`. A thread per delay is crude, but needs no timer
+/// from whatever runtime is polling.
+fn wake_at(until: Instant, waker: Waker) {
+  thread::spawn(move || {
+    thread::sleep(until.saturating_duration_since(Instant::now()));
+    waker.wake();
+  });
+}
+
+impl File {
+  /// Starts injecting faults under `plan`, from a fresh schedule, or stops
+  /// with `None`.
+  pub fn set_faults(&mut self, plan: Option<FaultPlan>) {
+    self.faults = plan.map(Faults::new);
+  }
+
+  pub fn faults(&self) -> Option<&Faults> {
+    self.faults.as_ref()
+  }
+
+  /// Called by every write before it changes anything.
+  pub(crate) fn inject_write_fault(&mut self) -> Result<(), FileError> {
+    let Some(faults) = &mut self.faults else { return Ok(()) };
+    faults.maybe_delay();
+    faults.writes += 1;
+    let write = faults.writes;
+    let random = faults.rng.chance(faults.plan.write_failure_rate);
+    if random || faults.plan.fail_nth_write == Some(write) {
+      faults.log.push(Fault::WriteFailed { write });
+      return Err(faults.plan.error.clone());
+    }
+    Ok(())
+  }
+
+  /// Called by `read` before reading into `buf`; may stall, and may hand back
+  /// a shorter buffer so the read comes up short.
+  pub(crate) fn inject_read_faults<'b>(&mut self, buf: &'b mut [u8]) -> &'b mut [u8] {
+    let available = self.data.len().saturating_sub(self.pos).min(buf.len());
+    let Some(faults) = &mut self.faults else { return buf };
+    faults.maybe_delay();
+    let short = faults.rng.chance(faults.plan.short_read_rate);
+    let given = 1 + faults.rng.below(available.saturating_sub(1));
+    if short && available > 1 {
+      faults.log.push(Fault::ShortRead { wanted: available, given });
+      return &mut buf[..given];
+    }
+    buf
+  }
+
+  /// Runs `op`, a read or write for one of the tokio traits, once any injected
+  /// delay is over, returning `Pending` meanwhile instead of sleeping.
+  pub(crate) fn poll_faulty<T>(&mut self, cx: &mut Context<'_>, op: impl FnOnce(&mut File) -> T) -> Poll<T> {
+    if let Some(faults) = &mut self.faults {
+      let until = match faults.waiting.take() {
+        Some(until) => Some(until),
+        None => faults.draw_delay().map(|delay| Instant::now() + delay),
+      };
+      if let Some(until) = until
+        && Instant::now() < until
+      {
+        faults.waiting = Some(until);
+        wake_at(until, cx.waker().clone());
+        return Poll::Pending;
+      }
+      faults.drawn = true;
+    }
+    let result = op(self);
+    if let Some(faults) = &mut self.faults {
+      faults.drawn = false;
+    }
+    Poll::Ready(result)
+  }
+
+  /// Called by `read` on the bytes it read from the current position.
+  pub(crate) fn corrupt_read(&mut self, read: &mut [u8]) {
+    let pos = self.pos;
+    let Some(faults) = &mut self.faults else { return };
+    let corrupt = faults.rng.chance(faults.plan.corrupt_rate);
+    let index = faults.rng.below(read.len());
+    let bit = faults.rng.below(8) as u8;
+    if corrupt && !read.is_empty() {
+      read[index] ^= 1 << bit;
+      faults.log.push(Fault::Corrupted { offset: pos + index, bit });
+    }
+  }
+}
+
+impl MemFs {
+  /// Injects faults into every file, now and as files are created or
+  /// inserted, each on its own schedule; `None` stops them all.
+  pub fn set_faults(&mut self, plan: Option<FaultPlan>) {
+    self.for_each_file_mut(|path, file| file.set_faults(plan.as_ref().map(|p| p.for_path(&path))));
+    self.faults = plan;
+  }
+
+  /// Gives a file arriving at `parent`/`name` the store's faults, if any.
+  pub(crate) fn inject_faults_into(&self, parent: &[String], name: &str, file: &mut File) {
+    if let Some(plan) = &self.faults {
+      let mut parts = parent.to_vec();
+      parts.push(name.to_string());
+      file.set_faults(Some(plan.for_path(&format!("/{}", parts.join("/")))));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Writes and reads `rounds` times under `plan`, returning the faults.
+  fn exercise(plan: FaultPlan, rounds: usize) -> Vec<Fault> {
+    let mut f = File::new_with_data("f", &[7; 64]);
+    f.set_faults(Some(plan));
+    f.open().unwrap();
+    for _ in 0..rounds {
+      let _ = f.write(b"ab");
+      f.seek(std::io::SeekFrom::Start(0)).unwrap();
+      let _ = f.read(&mut [0; 32]);
+    }
+    f.faults().unwrap().log().to_vec()
+  }
+
+  fn noisy(seed: u64) -> FaultPlan {
+    FaultPlan { seed, write_failure_rate: 0.3, short_read_rate: 0.3, corrupt_rate: 0.3, ..FaultPlan::default() }
+  }
+
+  #[test]
+  fn same_seed_same_faults() {
+    let log = exercise(noisy(1), 50);
+    assert!(log.len() > 10);
+    assert_eq!(exercise(noisy(1), 50), log);
+    assert_ne!(exercise(noisy(2), 50), log);
+  }
+
+  #[test]
+  fn nth_write_fails() {
+    let mut f = File::new("f");
+    f.set_faults(Some(FaultPlan { fail_nth_write: Some(2), ..FaultPlan::default() }));
+    f.open().unwrap();
+    assert_eq!(f.write(b"a"), Ok(1));
+    assert_eq!(f.insert_at(0, b"b"), Err(FileError::InjectedFault));
+    assert_eq!(f.truncate(0), Ok(()));
+    assert_eq!(f.faults().unwrap().writes(), 3);
+    assert_eq!(f.faults().unwrap().log(), [Fault::WriteFailed { write: 2 }]);
+    assert_eq!(&*f.contents(), b"");
+  }
+
+  #[test]
+  fn one_rate_leaves_the_rest_of_the_schedule() {
+    let reads = |faults: Vec<Fault>| faults.into_iter().filter(|f| !matches!(f, Fault::WriteFailed { .. })).collect::<Vec<_>>();
+    let plan = noisy(3);
+    let quiet = FaultPlan { write_failure_rate: 0.0, ..plan.clone() };
+    let log = reads(exercise(plan, 50));
+    assert!(!log.is_empty());
+    assert_eq!(reads(exercise(quiet, 50)), log);
+  }
+
+  #[test]
+  fn memfs_files_get_their_own_schedules() {
+    let mut fs = MemFs::new();
+    fs.create("/a").unwrap();
+    fs.set_faults(Some(FaultPlan { seed: 9, ..FaultPlan::default() }));
+    fs.create("/b").unwrap();
+    let a = fs.file("/a").unwrap().faults().unwrap().plan().seed;
+    let b = fs.file("/b").unwrap().faults().unwrap().plan().seed;
+    assert_ne!(a, b);
+    assert_eq!(a, FaultPlan { seed: 9, ..FaultPlan::default() }.for_path("/a").seed);
+    fs.set_faults(None);
+    assert!(fs.file("/b").unwrap().faults().is_none());
+  }
+
+  #[test]
+  fn delays_sleep_the_caller() {
+    let delay = Duration::from_millis(20);
+    let mut f = File::new("f");
+    f.set_faults(Some(FaultPlan { delay_rate: 1.0, delay, ..FaultPlan::default() }));
+    f.open().unwrap();
+    let start = Instant::now();
+    f.write(b"x").unwrap();
+    assert!(start.elapsed() >= delay);
+  }
+}
@@ -0,0 +1,268 @@
+//! Ways to print a `File` beyond the one-line `Display`: a long form with
+//! `{:#}`, JSON, an aligned table for many files, and a hex preview of the data.
+
+use std::fmt;
+use std::fmt::Display;
+
+use crate::compress::Compression;
+use crate::memfs::DirEntry;
+use crate::perm::Access;
+use crate::time::Rfc3339;
+use crate::File;
+
+/// Bytes shown by the long form and JSON.
+pub const PREVIEW_LEN: usize = 16;
+/// Bytes shown in a table row.
+const TABLE_PREVIEW_LEN: usize = 8;
+
+/// Up to `limit` leading bytes of a file as hex, then as text.
+#[derive(Debug,Clone,PartialEq)]
+pub struct HexPreview {
+  bytes: Vec<u8>,
+  /// The data goes on past `bytes`.
+  more: bool,
+}
+
+/// A file as a JSON object.
+#[derive(Debug)]
+pub struct Json<'a>(pub &'a File);
+
+/// Directory entries as aligned columns under a header, like `ls -l`.
+#[derive(Debug)]
+pub struct Table<'a> {
+  entries: Vec<DirEntry<'a>>,
+}
+
+impl HexPreview {
+  /// The hex digits alone, with no spacing or text column.
+  pub fn hex(&self) -> String {
+    self.bytes.iter().map(|b| format!("{:02x}", b)).collect()
+  }
+}
+
+impl Display for HexPreview {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let hex: Vec<String> = self.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    let text: String = self.bytes.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }).collect();
+    write!(f, "{} |{}|", hex.join(" "), text)?;
+    if self.more {
+      write!(f, " ...")?;
+    }
+    Ok(())
+  }
+}
+
+/// `s` as a JSON string literal.
+fn json_str(s: &str) -> String {
+  let mut out = String::from("\"");
+  for c in s.chars() {
+    match c {
+      '"' => out.push_str("\\\""),
+      '\\' => out.push_str("\\\\"),
+      '\n' => out.push_str("\\n"),
+      '\r' => out.push_str("\\r"),
+      '\t' => out.push_str("\\t"),
+      c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+      c => out.push(c),
+    }
+  }
+  out.push('"');
+  out
+}
+
+impl Display for Json<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let file = self.0;
+    let perms = &file.perms;
+    let preview = match file.preview(PREVIEW_LEN) {
+      Some(preview) => json_str(&preview.hex()),
+      None => "null".to_string(),
+    };
+    let xattrs: Vec<String> = file.xattrs().map(|(k, v)| format!("{}:{}", json_str(k), json_str(v))).collect();
+    let xattrs = xattrs.join(",");
+    write!(f, "{{\"name\":{},\"state\":{},\"mode\":{},\"owner\":{},\"group\":{},\
+               \"size\":{},\"stored_size\":{},\"compression\":{},\"encrypted\":{},\
+               \"created\":{},\"modified\":{},\"accessed\":{},\"xattrs\":{{{}}},\"preview\":{}}}",
+           json_str(&file.name), json_str(&file.state.to_string()), json_str(&perms.mode_string()),
+           json_str(&perms.owner), json_str(&perms.group), file.len(), file.stored_len(),
+           json_str(&file.compression.to_string()), file.encrypted,
+           json_str(&Rfc3339(file.times.created).to_string()), json_str(&Rfc3339(file.times.modified).to_string()),
+           json_str(&Rfc3339(file.times.accessed).to_string()), xattrs, preview)
+  }
+}
+
+impl<'a> Table<'a> {
+  pub fn new(entries: impl IntoIterator<Item = DirEntry<'a>>) -> Table<'a> {
+    Table { entries: entries.into_iter().collect() }
+  }
+
+  pub fn of_files(files: impl IntoIterator<Item = &'a File>) -> Table<'a> {
+    Table::new(files.into_iter().map(DirEntry::File))
+  }
+}
+
+impl Display for Table<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let header = ["MODE", "OWNER", "GROUP", "SIZE", "MODIFIED", "STATE", "NAME", "PREVIEW"];
+    let rows: Vec<[String; 8]> = self.entries.iter().map(|entry| match entry {
+      DirEntry::File(file) => [
+        file.perms.mode_string(),
+        file.perms.owner.clone(),
+        file.perms.group.clone(),
+        file.len().to_string(),
+        Rfc3339(file.times.modified).to_string(),
+        file.state.to_string(),
+        file.name.clone(),
+        file.preview(TABLE_PREVIEW_LEN).map(|p| p.hex()).unwrap_or_default(),
+      ],
+      // Directories and symlinks have no owner or mode of their own; only
+      // their type is shown.
+      DirEntry::Dir(name) => [
+        "d".to_string(),
+        "-".to_string(),
+        "-".to_string(),
+        "-".to_string(),
+        "-".to_string(),
+        "-".to_string(),
+        format!("{}/", name),
+        String::new(),
+      ],
+      DirEntry::Symlink { name, target } => [
+        "l".to_string(),
+        "-".to_string(),
+        "-".to_string(),
+        target.len().to_string(),
+        "-".to_string(),
+        "-".to_string(),
+        format!("{} -> {}", name, target),
+        String::new(),
+      ],
+    }).collect();
+
+    let mut widths = header.map(str::len);
+    for row in &rows {
+      for (width, cell) in widths.iter_mut().zip(row) {
+        *width = (*width).max(cell.chars().count());
+      }
+    }
+    let mut line = |cells: &[&str]| -> fmt::Result {
+      let mut out = String::new();
+      for (i, cell) in cells.iter().enumerate() {
+        if i > 0 {
+          out.push_str("  ");
+        }
+        // Sizes line up on the right, everything else on the left.
+        if i == 3 {
+          out.push_str(&format!("{:>width$}", cell, width = widths[i]));
+        } else {
+          out.push_str(&format!("{:<width$}", cell, width = widths[i]));
+        }
+      }
+      writeln!(f, "{}", out.trim_end())
+    };
+    line(&header)?;
+    for row in &rows {
+      line(&row.each_ref().map(String::as_str))?;
+    }
+    Ok(())
+  }
+}
+
+impl File {
+  /// The first `limit` bytes, or `None` if the user may not read them or they
+  /// are ciphertext.
+  pub fn preview(&self, limit: usize) -> Option<HexPreview> {
+    if self.encrypted || self.check_access(Access::Read).is_err() {
+      return None;
+    }
+    let mut bytes = vec![0; limit.min(self.len())];
+    let n = self.data.read_at(0, &mut bytes);
+    bytes.truncate(n);
+    Some(HexPreview { more: self.len() > n, bytes })
+  }
+
+  pub fn json(&self) -> Json<'_> {
+    Json(self)
+  }
+
+  /// The `{:#}` form: one field per line.
+  pub(crate) fn fmt_long(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    writeln!(f, "{} ({})", self.name, self.state)?;
+    writeln!(f, "  perms:    {}", self.perms)?;
+    write!(f, "  size:     {} bytes", self.len())?;
+    if self.compression != Compression::None {
+      write!(f, " ({} {} stored)", self.compression, self.stored_len())?;
+    } else if self.stored_len() != self.len() {
+      write!(f, " ({} allocated)", self.stored_len())?;
+    }
+    if self.encrypted {
+      write!(f, " [encrypted]")?;
+    }
+    writeln!(f)?;
+    writeln!(f, "  created:  {}", Rfc3339(self.times.created))?;
+    writeln!(f, "  modified: {}", Rfc3339(self.times.modified))?;
+    writeln!(f, "  accessed: {}", Rfc3339(self.times.accessed))?;
+    for (key, value) in self.xattrs() {
+      writeln!(f, "  xattr:    {}={}", key, value)?;
+    }
+    match self.preview(PREVIEW_LEN) {
+      Some(preview) => write!(f, "  preview:  {}", preview),
+      None => write!(f, "  preview:  (unavailable)"),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::UNIX_EPOCH;
+
+  use crate::memfs::MemFs;
+  use crate::time::Timestamps;
+
+  #[test]
+  fn preview_marks_more_and_hides_ciphertext() {
+    let f = File::new_with_data("f", b"hi\nthere");
+    let preview = f.preview(3).unwrap();
+    assert_eq!(preview.hex(), "68690a");
+    assert_eq!(preview.to_string(), "68 69 0a |hi.| ...");
+    assert_eq!(f.preview(16).unwrap().to_string(), "68 69 0a 74 68 65 72 65 |hi.there|");
+    let mut secret = File::new_with_data("s", b"hi");
+    secret.encrypt(&crate::crypt::FileKey::generate()).unwrap();
+    assert_eq!(secret.preview(16), None);
+  }
+
+  #[test]
+  fn json_escapes_and_lists_fields() {
+    let mut f = File::new_with_data("say \"hi\"\n", b"hi");
+    f.set_timestamps(Timestamps { created: UNIX_EPOCH, modified: UNIX_EPOCH, accessed: UNIX_EPOCH });
+    let json = f.json().to_string();
+    assert!(json.starts_with("{\"name\":\"say \\\"hi\\\"\\n\","), "{}", json);
+    assert!(json.contains("\"size\":2,\"stored_size\":2,"));
+    assert!(json.contains("\"encrypted\":false,\"created\":\"1970-01-01T00:00:00Z\","));
+    assert!(json.ends_with("\"xattrs\":{},\"preview\":\"6869\"}"));
+  }
+
+  #[test]
+  fn table_aligns_columns() {
+    let mut fs = MemFs::new();
+    fs.mkdir("/d").unwrap();
+    fs.insert("/d/f", File::new_with_data("f", b"abc")).unwrap();
+    fs.mkdir("/d/sub").unwrap();
+    fs.symlink("f", "/d/l").unwrap();
+    let table = Table::new(fs.list_dir("/d").unwrap()).to_string();
+    let lines: Vec<&str> = table.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[0].starts_with("MODE"));
+    let size = lines[0].find("SIZE").unwrap() + "SIZE".len();
+    for line in &lines[1..] {
+      let cells: Vec<&str> = line.split_whitespace().collect();
+      assert!(line[..size].ends_with(cells[3]), "{}", line);
+      match cells[0] {
+        "d" => assert_eq!(&cells[1..], ["-", "-", "-", "-", "-", "sub/"]),
+        "l" => assert_eq!(&cells[1..], ["-", "-", "1", "-", "-", "l", "->", "f"]),
+        _ => assert_eq!((cells[3], cells[6], cells[7]), ("3", "f", "616263")),
+      }
+    }
+  }
+}
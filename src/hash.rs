@@ -0,0 +1,348 @@
+//! SHA-256 content hashes of `File` data, and deduplication of identical files
+//! in a `MemFs` into shared, copy-on-write blobs.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Display;
+use std::ops::Range;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+
+use sha2::{Digest, Sha256};
+
+use crate::memfs::MemFs;
+use crate::storage::Storage;
+use crate::{File, FileError};
+
+/// Bytes read at a time when hashing a non-contiguous backend.
+const HASH_CHUNK: usize = 64 * 1024;
+
+#[derive(Clone,Copy,PartialEq,Eq,Hash,PartialOrd,Ord)]
+pub struct ContentHash([u8; 32]);
+
+/// A running hash of the data, kept up to date as it is appended to. Any other
+/// change drops it, and the next `content_hash` starts over.
+#[derive(Default)]
+pub struct ContentHasher {
+  /// The hash state and how many leading bytes it has seen.
+  state: Mutex<Option<(Sha256, usize)>>,
+}
+
+/// A `Storage` whose bytes may be shared with other files; the first write
+/// takes a private copy.
+#[derive(Debug,Clone)]
+pub struct Shared(Arc<Vec<u8>>);
+
+/// Content-addressed blobs shared by a `MemFs`'s deduplicated files. Blobs go
+/// away once no file uses them.
+#[derive(Debug,Default)]
+pub struct ContentStore {
+  blobs: HashMap<ContentHash, Weak<Vec<u8>>>,
+}
+
+/// What a `MemFs::dedup` pass found.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct DedupReport {
+  pub files: usize,
+  /// Distinct contents among those files.
+  pub unique: usize,
+  pub logical_bytes: usize,
+  /// Bytes held once each distinct content is stored only once.
+  pub stored_bytes: usize,
+}
+
+impl Display for ContentHash {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    for byte in self.0 {
+      write!(f, "{:02x}", byte)?;
+    }
+    Ok(())
+  }
+}
+
+impl fmt::Debug for ContentHash {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "ContentHash({})", self)
+  }
+}
+
+impl ContentHash {
+  pub fn of(bytes: &[u8]) -> ContentHash {
+    ContentHash(Sha256::digest(bytes).into())
+  }
+
+  pub fn as_bytes(&self) -> &[u8; 32] {
+    &self.0
+  }
+}
+
+impl fmt::Debug for ContentHasher {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match &*self.state() {
+      Some((_, n)) => write!(f, "ContentHasher({} bytes)", n),
+      None => write!(f, "ContentHasher(stale)"),
+    }
+  }
+}
+
+fn feed(hasher: &mut Sha256, data: &dyn Storage, range: Range<usize>) {
+  if let Some(slice) = data.as_slice() {
+    hasher.update(&slice[range]);
+    return;
+  }
+  let mut buf = vec![0; HASH_CHUNK.min(range.len())];
+  let mut pos = range.start;
+  while pos < range.end {
+    let want = (range.end - pos).min(buf.len());
+    let n = data.read_at(pos, &mut buf[..want]);
+    if n == 0 {
+      break;
+    }
+    hasher.update(&buf[..n]);
+    pos += n;
+  }
+}
+
+impl ContentHasher {
+  fn state(&self) -> MutexGuard<'_, Option<(Sha256, usize)>> {
+    self.state.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  pub fn invalidate(&self) {
+    *self.state() = None;
+  }
+
+  /// Catches up after `data` changed at `offset`: appended bytes are fed in,
+  /// anything that rewrites already hashed bytes drops the state.
+  pub fn update(&self, data: &dyn Storage, offset: usize) {
+    let mut state = self.state();
+    if let Some((hasher, seen)) = state.as_mut() {
+      if offset < *seen || data.len() < *seen {
+        *state = None;
+      } else {
+        feed(hasher, data, *seen..data.len());
+        *seen = data.len();
+      }
+    }
+  }
+
+  /// The hash of `data`, rebuilding the running state if it was dropped.
+  pub fn finish(&self, data: &dyn Storage) -> ContentHash {
+    let mut state = self.state();
+    let (hasher, seen) = state.get_or_insert_with(|| (Sha256::new(), 0));
+    if *seen != data.len() {
+      feed(hasher, data, *seen..data.len());
+      *seen = data.len();
+    }
+    ContentHash(hasher.clone().finalize().into())
+  }
+}
+
+impl Shared {
+  pub fn is_shared(&self) -> bool {
+    Arc::strong_count(&self.0) > 1
+  }
+}
+
+impl Storage for Shared {
+  fn len(&self) -> usize {
+    self.0.len()
+  }
+
+  fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+    Storage::read_at(&*self.0, offset, buf)
+  }
+
+  fn write_at(&mut self, offset: usize, buf: &[u8]) -> Result<(), FileError> {
+    Storage::write_at(Arc::make_mut(&mut self.0), offset, buf)
+  }
+
+  fn insert(&mut self, offset: usize, buf: &[u8]) -> Result<(), FileError> {
+    Storage::insert(Arc::make_mut(&mut self.0), offset, buf)
+  }
+
+  fn remove(&mut self, range: Range<usize>) -> Result<(), FileError> {
+    Storage::remove(Arc::make_mut(&mut self.0), range)
+  }
+
+  fn resize(&mut self, len: usize) -> Result<(), FileError> {
+    Storage::resize(Arc::make_mut(&mut self.0), len)
+  }
+
+  fn as_slice(&self) -> Option<&[u8]> {
+    Some(&self.0)
+  }
+
+  fn shared_id(&self) -> Option<usize> {
+    Some(Arc::as_ptr(&self.0) as usize)
+  }
+}
+
+impl ContentStore {
+  /// Distinct contents still in use.
+  pub fn len(&self) -> usize {
+    self.blobs.values().filter(|b| b.strong_count() > 0).count()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  pub fn get(&self, hash: &ContentHash) -> Option<Arc<Vec<u8>>> {
+    self.blobs.get(hash).and_then(Weak::upgrade)
+  }
+
+  /// The stored blob equal to `contents`, adding it if it is new.
+  fn intern(&mut self, hash: ContentHash, contents: &[u8]) -> Arc<Vec<u8>> {
+    match self.get(&hash) {
+      Some(blob) if blob.as_slice() == contents => blob,
+      _ => {
+        let blob = Arc::new(contents.to_vec());
+        self.blobs.insert(hash, Arc::downgrade(&blob));
+        blob
+      }
+    }
+  }
+
+  fn prune(&mut self) {
+    self.blobs.retain(|_, blob| blob.strong_count() > 0);
+  }
+}
+
+impl Display for DedupReport {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{} files, {} unique: {} bytes stored as {} (saved {})",
+           self.files, self.unique, self.logical_bytes, self.stored_bytes, self.saved_bytes())
+  }
+}
+
+impl DedupReport {
+  pub fn saved_bytes(&self) -> usize {
+    self.logical_bytes - self.stored_bytes
+  }
+}
+
+impl File {
+  /// SHA-256 of the data as stored, so of the ciphertext for encrypted files.
+  /// Cheap after appends; after other edits it rehashes the data once. Data
+  /// that has ever been hard linked is rehashed every time, since other links
+  /// may have changed it; `hard_link` drops the running state for good.
+  pub fn content_hash(&self) -> ContentHash {
+    if self.data.as_linked().is_some() {
+      return ContentHash::of(&self.data.contents());
+    }
+    self.hasher.finish(&*self.data)
+  }
+}
+
+impl MemFs {
+  pub fn content_store(&self) -> &ContentStore {
+    &self.store
+  }
+
+  /// Points every file with the same contents at one shared blob. Only files
+  /// on contiguous backends take part; compressed, sparse, rope and hard linked
+  /// files are left as they are. Shared data is copied again on the first write.
+  pub fn dedup(&mut self) -> DedupReport {
+    let mut report = DedupReport { files: 0, unique: 0, logical_bytes: 0, stored_bytes: 0 };
+    let mut seen = HashMap::new();
+    let mut store = std::mem::take(&mut self.store);
+    store.prune();
+    self.for_each_file_mut(|_, file| {
+      if file.data.as_slice().is_none() {
+        return;
+      }
+      let hash = file.content_hash();
+      let blob = store.intern(hash, &file.data.contents());
+      report.files += 1;
+      report.logical_bytes += blob.len();
+      if seen.insert(Arc::as_ptr(&blob), blob.len()).is_none() {
+        report.unique += 1;
+        report.stored_bytes += blob.len();
+      }
+      file.data = Box::new(Shared(blob));
+    });
+    self.store = store;
+    report
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn append(file: &mut File, data: &[u8]) {
+    file.open_append().unwrap();
+    file.write(data).unwrap();
+    file.close().unwrap();
+  }
+
+  #[test]
+  fn tracks_appends_and_edits() {
+    let mut f = File::new("f");
+    assert_eq!(f.content_hash(), ContentHash::of(b""));
+    append(&mut f, b"hello");
+    assert_eq!(f.content_hash(), ContentHash::of(b"hello"));
+    append(&mut f, b" world");
+    assert_eq!(f.content_hash(), ContentHash::of(b"hello world"));
+    f.open().unwrap();
+    f.remove_range(0..6).unwrap();
+    f.close().unwrap();
+    assert_eq!(f.content_hash(), ContentHash::of(b"world"));
+    assert_eq!(f.content_hash().to_string().len(), 64);
+  }
+
+  #[test]
+  fn sees_writes_through_links() {
+    let mut a = File::new_with_data("a", b"hello");
+    assert_eq!(a.content_hash(), ContentHash::of(b"hello"));
+    let mut b = a.hard_link("b").unwrap();
+    b.open().unwrap();
+    b.write(b"J").unwrap();
+    b.close().unwrap();
+    assert_eq!(a.content_hash(), ContentHash::of(b"Jello"));
+    drop(b);
+    append(&mut a, b"!");
+    assert_eq!(a.content_hash(), ContentHash::of(b"Jello!"));
+  }
+
+  #[test]
+  fn dedup_shares_until_written() {
+    let mut fs = MemFs::new();
+    for name in ["a", "b", "c"] {
+      fs.insert(&format!("/{}", name), File::new_with_data(name, b"same")).unwrap();
+    }
+    fs.insert("/d", File::new_with_data("d", b"other")).unwrap();
+    let report = fs.dedup();
+    assert_eq!((report.files, report.unique, report.saved_bytes()), (4, 2, 8));
+    assert_eq!(fs.content_store().len(), 2);
+    let a = fs.file_mut("/a").unwrap();
+    append(a, b"!");
+    assert_eq!(&*fs.file("/a").unwrap().contents(), b"same!");
+    assert_eq!(&*fs.file("/b").unwrap().contents(), b"same");
+    fs.remove("/d").unwrap();
+    assert!(fs.content_store().get(&ContentHash::of(b"other")).is_none());
+  }
+
+  #[test]
+  fn usage_counts_each_blob_once() {
+    let mut fs = MemFs::new();
+    for name in ["a", "b", "c"] {
+      fs.insert(&format!("/{}", name), File::new_with_data(name, b"same")).unwrap();
+    }
+    assert_eq!(fs.usage().stored_bytes, 12);
+    fs.dedup();
+    fs.link("/a", "/d").unwrap();
+    assert_eq!(fs.usage().stored_bytes, 4);
+  }
+
+  #[test]
+  fn dedup_leaves_other_backends_alone() {
+    let mut fs = MemFs::new();
+    fs.insert("/a", File::new_with_data("a", b"same")).unwrap();
+    fs.insert("/b", File::new_with_data("b", b"same")).unwrap();
+    fs.file_mut("/b").unwrap().make_sparse().unwrap();
+    assert_eq!(fs.dedup().files, 1);
+    assert_eq!(fs.file("/b").unwrap().data.as_slice(), None);
+    assert_eq!(&*fs.file("/b").unwrap().contents(), b"same");
+  }
+}
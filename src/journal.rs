@@ -0,0 +1,447 @@
+//! A write-ahead journal for files with a backing path.
+//!
+//! Every mutation is appended to `.<name>.journal` next to the backing file and
+//! synced before it touches the in-memory data. `flush` is the checkpoint: it
+//! replaces the backing file, then starts a fresh journal. `File::recover`
+//! loads the backing file and replays the journal onto it, so after a crash the
+//! file holds everything up to the last complete record.
+//!
+//! The journal starts with a header naming the length and CRC-32 of the data it
+//! applies to. A journal whose header doesn't match the backing file is left
+//! over from a checkpoint that crashed after the rename, and is ignored. Each
+//! record is a little-endian `u32` payload length, the payload's CRC-32, then the
+//! payload; the first short or mismatching record marks a torn write and ends
+//! the replay.
+
+use std::fs;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::archive::crc32;
+use crate::crypt::load_sealed_flag;
+use crate::persist::write_atomic;
+use crate::storage::Storage;
+use crate::{File, FileError};
+
+const MAGIC: &[u8; 4] = b"MFSJ";
+const HEADER_LEN: usize = 16;
+const RECORD_HEADER_LEN: usize = 8;
+
+/// One logged mutation, as applied to the data.
+#[derive(Debug,Clone,PartialEq)]
+pub enum Op<'a> {
+  Write { offset: usize, data: &'a [u8] },
+  Insert { offset: usize, data: &'a [u8] },
+  Remove(Range<usize>),
+  Resize(usize),
+  /// The whole data was replaced with plaintext, e.g. by `rollback` or `decrypt`.
+  Replace(&'a [u8]),
+  /// The whole data was replaced with ciphertext, by `encrypt` or `write_encrypted`.
+  Seal(&'a [u8]),
+}
+
+/// The open journal of a `File`, positioned for appending.
+#[derive(Debug)]
+pub struct Journal {
+  path: PathBuf,
+  file: fs::File,
+}
+
+/// What `replay` rebuilt from a journal.
+struct Replay {
+  data: Vec<u8>,
+  applied: usize,
+  /// Whether the last `Replace` or `Seal` left the data encrypted, if there was one.
+  encrypted: Option<bool>,
+  /// Bytes of the journal up to the end of the last complete record, or `None`
+  /// if the journal is stale.
+  valid_len: Option<usize>,
+}
+
+/// Where the journal for the backing file `path` lives.
+pub fn journal_path(path: &Path) -> PathBuf {
+  let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+  path.with_file_name(format!(".{}.journal", name))
+}
+
+fn corrupt(what: &str) -> FileError {
+  FileError::Corrupt(format!("journal: {}", what))
+}
+
+fn header(base: &[u8]) -> Vec<u8> {
+  let mut out = MAGIC.to_vec();
+  out.extend((base.len() as u64).to_le_bytes());
+  out.extend(crc32(base).to_le_bytes());
+  out
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> Result<usize, FileError> {
+  let field = bytes.get(at..at + 8).ok_or_else(|| corrupt("truncated record"))?;
+  usize::try_from(u64::from_le_bytes(field.try_into().unwrap())).map_err(|_| corrupt("offset too large"))
+}
+
+impl<'a> Op<'a> {
+  fn encode(&self) -> Vec<u8> {
+    let mut out = Vec::new();
+    match self {
+      Op::Write { offset, data } | Op::Insert { offset, data } => {
+        out.push(if matches!(self, Op::Write { .. }) { 1 } else { 2 });
+        out.extend((*offset as u64).to_le_bytes());
+        out.extend_from_slice(data);
+      }
+      Op::Remove(range) => {
+        out.push(3);
+        out.extend((range.start as u64).to_le_bytes());
+        out.extend((range.end as u64).to_le_bytes());
+      }
+      Op::Resize(len) => {
+        out.push(4);
+        out.extend((*len as u64).to_le_bytes());
+      }
+      Op::Replace(data) | Op::Seal(data) => {
+        out.push(if matches!(self, Op::Replace(_)) { 5 } else { 6 });
+        out.extend_from_slice(data);
+      }
+    }
+    out
+  }
+
+  fn decode(payload: &'a [u8]) -> Result<Op<'a>, FileError> {
+    let (&tag, _) = payload.split_first().ok_or_else(|| corrupt("empty record"))?;
+    Ok(match tag {
+      1 => Op::Write { offset: u64_at(payload, 1)?, data: &payload[9..] },
+      2 => Op::Insert { offset: u64_at(payload, 1)?, data: &payload[9..] },
+      3 => Op::Remove(u64_at(payload, 1)?..u64_at(payload, 9)?),
+      4 => Op::Resize(u64_at(payload, 1)?),
+      5 => Op::Replace(&payload[1..]),
+      6 => Op::Seal(&payload[1..]),
+      _ => return Err(corrupt("unknown record type")),
+    })
+  }
+
+  /// Applies the op the way the live file did. Only ops that passed the file's
+  /// checks are logged, so one that fails here means the journal is corrupt.
+  fn apply(&self, data: &mut dyn Storage) -> Result<(), FileError> {
+    match self {
+      Op::Write { offset, data: buf } => data.write_at(*offset, buf),
+      Op::Insert { offset, data: buf } => data.insert(*offset, buf),
+      Op::Remove(range) => data.remove(range.clone()),
+      Op::Resize(len) => data.resize(*len),
+      Op::Replace(buf) | Op::Seal(buf) => {
+        data.resize(0)?;
+        data.write_at(0, buf)
+      }
+    }
+  }
+}
+
+impl Journal {
+  /// Starts an empty journal for `base`, replacing any old one.
+  fn create(path: &Path, base: &[u8]) -> Result<Journal, FileError> {
+    write_atomic(path, &header(base))?;
+    Journal::open(path)
+  }
+
+  fn open(path: &Path) -> Result<Journal, FileError> {
+    let file = fs::OpenOptions::new().append(true).open(path)?;
+    Ok(Journal { path: path.to_path_buf(), file })
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  /// Writes `op` and waits for it to reach the disk.
+  fn append(&mut self, op: &Op) -> Result<(), FileError> {
+    let payload = op.encode();
+    let len = u32::try_from(payload.len()).map_err(|_| FileError::OutOfSpace)?;
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+    record.extend(len.to_le_bytes());
+    record.extend(crc32(&payload).to_le_bytes());
+    record.extend(payload);
+    self.file.write_all(&record)?;
+    self.file.sync_data()?;
+    Ok(())
+  }
+
+  fn discard(self) {
+    let _ = fs::remove_file(&self.path);
+  }
+}
+
+/// Rebuilds the data from `base` and the raw `journal`.
+fn replay(journal: &[u8], base: &[u8]) -> Result<Replay, FileError> {
+  if journal.len() < HEADER_LEN || &journal[..4] != MAGIC {
+    return Err(corrupt("bad header"));
+  }
+  if journal[..HEADER_LEN] != header(base)[..] {
+    return Ok(Replay { data: base.to_vec(), applied: 0, encrypted: None, valid_len: None });
+  }
+  let mut data = base.to_vec();
+  let mut applied = 0;
+  let mut encrypted = None;
+  let mut pos = HEADER_LEN;
+  while let Some(record) = journal.get(pos..pos + RECORD_HEADER_LEN) {
+    let len = u32::from_le_bytes(record[..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(record[4..].try_into().unwrap());
+    let start = pos + RECORD_HEADER_LEN;
+    let Some(payload) = journal.get(start..start + len) else { break };
+    if crc32(payload) != crc {
+      break;
+    }
+    // Ops are checked before they are logged, so a logged op that doesn't
+    // apply means the journal doesn't belong to this data.
+    let op = Op::decode(payload)?;
+    op.apply(&mut data).map_err(|e| corrupt(&format!("record {} doesn't apply: {}", applied + 1, e)))?;
+    match op {
+      Op::Replace(_) => encrypted = Some(false),
+      Op::Seal(_) => encrypted = Some(true),
+      _ => {}
+    }
+    applied += 1;
+    pos = start + len;
+  }
+  Ok(Replay { data, applied, encrypted, valid_len: Some(pos) })
+}
+
+impl File {
+  /// Starts journaling writes to the backing file, checkpointing first.
+  pub fn enable_journal(&mut self) -> Result<(), FileError> {
+    let path = self.backing.clone().ok_or(FileError::InvalidPath)?;
+    if self.journal.is_some() {
+      return Ok(());
+    }
+    self.flush()?;
+    self.journal = Some(Journal::create(&journal_path(&path), &self.data.contents())?);
+    Ok(())
+  }
+
+  /// Checkpoints and removes the journal.
+  pub fn disable_journal(&mut self) -> Result<(), FileError> {
+    self.flush()?;
+    if let Some(journal) = self.journal.take() {
+      journal.discard();
+    }
+    Ok(())
+  }
+
+  pub fn journal(&self) -> Option<&Journal> {
+    self.journal.as_ref()
+  }
+
+  /// Appends `op` to the journal, if any. Callers check that the op applies,
+  /// then log it before applying it.
+  pub(crate) fn log(&mut self, op: Op) -> Result<(), FileError> {
+    match &mut self.journal {
+      Some(journal) => journal.append(&op),
+      None => Ok(()),
+    }
+  }
+
+  /// Starts a fresh journal for data just written to the backing file.
+  pub(crate) fn reset_journal(&mut self) -> Result<(), FileError> {
+    if let Some(journal) = &self.journal {
+      let path = journal.path.clone();
+      self.journal = Some(Journal::create(&path, &self.data.contents())?);
+    }
+    Ok(())
+  }
+
+  /// Hands over the journal when the backing file moves.
+  pub(crate) fn move_journal(&mut self) -> Result<(), FileError> {
+    if let Some(journal) = self.journal.take() {
+      journal.discard();
+      self.enable_journal()?;
+    }
+    Ok(())
+  }
+
+  /// Like `load`, but replays the journal left by a journaled file, if there is
+  /// one, and keeps journaling. Replayed changes are dirty until the next flush.
+  pub fn recover<P: AsRef<Path>>(path: P) -> Result<File, FileError> {
+    let path = path.as_ref();
+    let jpath = journal_path(path);
+    let journal = match fs::read(&jpath) {
+      Ok(journal) => journal,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return File::load(path),
+      Err(e) => return Err(e.into()),
+    };
+    // A file journaled before its first checkpoint has no backing file yet.
+    let base = match fs::read(path) {
+      Ok(base) => base,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+      Err(e) => return Err(e.into()),
+    };
+    let replay = replay(&journal, &base)?;
+    let name = path.file_name().ok_or(FileError::InvalidPath)?.to_string_lossy();
+    let mut file = File::new_with_data(&name, &replay.data);
+    file.encrypted = match replay.encrypted {
+      Some(encrypted) => encrypted,
+      None => load_sealed_flag(path)?,
+    };
+    file.backing = Some(path.to_path_buf());
+    file.dirty = replay.applied > 0;
+    file.journal = Some(match replay.valid_len {
+      Some(len) => {
+        // Drop a torn record so new ones follow the last good one.
+        let out = fs::OpenOptions::new().write(true).open(&jpath)?;
+        out.set_len(len as u64)?;
+        out.sync_all()?;
+        Journal::open(&jpath)?
+      }
+      None => Journal::create(&jpath, &base)?,
+    });
+    Ok(file)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Applies `op` through the regular `File` API, so it is journaled as usual.
+  fn run(file: &mut File, op: &Op) -> Result<(), FileError> {
+    match op {
+      Op::Write { offset, data } => {
+        file.seek(io::SeekFrom::Start(*offset as u64))?;
+        file.write(data).map(|_| ())
+      }
+      Op::Insert { offset, data } => file.insert_at(*offset, data),
+      Op::Remove(range) => file.remove_range(range.clone()),
+      Op::Resize(len) => file.truncate(*len),
+      Op::Replace(data) => file.replace_data(data),
+      Op::Seal(_) => panic!("sealing needs a key"),
+    }
+  }
+
+  /// Crash-tests the journal in `dir`: runs `ops` on a journaled file, then for
+  /// every byte the journal reached, restores the disk as a crash at that point
+  /// would have left it and checks that `recover` yields the data as of the last
+  /// complete op. Also covers a crash between the two steps of a checkpoint.
+  /// Returns how many crash points were checked.
+  fn simulate_crashes(dir: &Path, ops: &[Op]) -> Result<usize, FileError> {
+    let path = dir.join("crash.dat");
+    let jpath = journal_path(&path);
+    let mut file = File::new("crash.dat");
+    file.sync_to(&path)?;
+    file.enable_journal()?;
+    file.open()?;
+    let base = fs::read(&path)?;
+
+    // The journal length after each op, and the data it should recover to.
+    let mut states = vec![(HEADER_LEN, base.clone())];
+    for op in ops {
+      let before = fs::metadata(&jpath)?.len();
+      if run(&mut file, op).is_err() && fs::metadata(&jpath)?.len() != before {
+        return Err(corrupt(&format!("failed {:?} was logged", op)));
+      }
+      states.push((fs::metadata(&jpath)?.len() as usize, file.contents().into_owned()));
+    }
+    let journal = fs::read(&jpath)?;
+    let last = file.contents().into_owned();
+
+    let mut checked = 0;
+    let mut check = |data: &[u8], journal: &[u8], expected: &[u8], what: String| -> Result<(), FileError> {
+      fs::write(&path, data)?;
+      fs::write(&jpath, journal)?;
+      let recovered = File::recover(&path)?;
+      if recovered.contents() != expected {
+        return Err(corrupt(&format!("wrong data after a crash {}", what)));
+      }
+      checked += 1;
+      Ok(())
+    };
+    for cut in HEADER_LEN..=journal.len() {
+      let expected = &states.iter().rev().find(|(len, _)| *len <= cut).unwrap().1;
+      check(&base, &journal[..cut], expected, format!("at journal byte {}", cut))?;
+    }
+    check(&last, &journal, &last, "after the checkpoint rename".to_string())?;
+
+    // A completed checkpoint leaves nothing to replay.
+    let mut file = File::recover(&path)?;
+    file.flush()?;
+    drop(file);
+    check(&fs::read(&path)?, &fs::read(&jpath)?, &last, "after a checkpoint".to_string())?;
+
+    fs::remove_file(&path)?;
+    fs::remove_file(&jpath)?;
+    Ok(checked)
+  }
+
+  fn scratch(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("memfs-journal-{}-{}", std::process::id(), test));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  #[test]
+  fn recovers_at_every_crash_point() {
+    let dir = scratch("crashes");
+    let ops = [
+      Op::Write { offset: 0, data: b"hello world" },
+      Op::Insert { offset: 5, data: b"," },
+      Op::Remove(0..1),
+      Op::Resize(16),
+      // Out of range, so it fails and must leave no record.
+      Op::Remove(20..30),
+      Op::Replace(b"fresh start"),
+    ];
+    let checked = simulate_crashes(&dir, &ops).unwrap();
+    assert!(checked > 50);
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn recover_replays_unflushed_writes() {
+    let dir = scratch("replay");
+    let path = dir.join("a.txt");
+    let mut f = File::new("a.txt");
+    f.sync_to(&path).unwrap();
+    f.enable_journal().unwrap();
+    f.open().unwrap();
+    f.write(b"not flushed").unwrap();
+    drop(f);
+    assert_eq!(fs::read(&path).unwrap(), b"");
+    let mut f = File::recover(&path).unwrap();
+    assert_eq!(&*f.contents(), b"not flushed");
+    assert!(f.is_dirty());
+    f.flush().unwrap();
+    assert_eq!(fs::read(&path).unwrap(), b"not flushed");
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn failed_ops_leave_no_record() {
+    let dir = scratch("failed");
+    let path = dir.join("a.txt");
+    let mut f = File::new_with_data("a.txt", b"abc");
+    f.sync_to(&path).unwrap();
+    f.enable_journal().unwrap();
+    f.open().unwrap();
+    assert_eq!(f.insert_at(4, b"x"), Err(FileError::InvalidSeek));
+    assert_eq!(f.remove_range(2..5), Err(FileError::InvalidSeek));
+    assert_eq!(fs::read(journal_path(&path)).unwrap().len(), HEADER_LEN);
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn replay_reports_records_that_dont_apply() {
+    let dir = scratch("bad-record");
+    let path = dir.join("a.txt");
+    fs::write(&path, b"abc").unwrap();
+    let mut journal = Journal::create(&journal_path(&path), b"abc").unwrap();
+    journal.append(&Op::Remove(2..5)).unwrap();
+    assert!(matches!(File::recover(&path), Err(FileError::Corrupt(_))));
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn ops_survive_encoding() {
+    let ops = [Op::Write { offset: 3, data: b"abc" }, Op::Remove(1..2), Op::Resize(9), Op::Replace(b""), Op::Seal(b"x")];
+    for op in ops {
+      assert_eq!(Op::decode(&op.encode()).unwrap(), op);
+    }
+  }
+}
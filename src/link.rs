@@ -0,0 +1,305 @@
+//! Hard links: several `File`s sharing one data buffer. Only the bytes are
+//! shared, so a write through one name is seen through every other.
+//!
+//! Unlike links on a real filesystem, these share no inode metadata. Each link
+//! starts with a copy of the source's permissions, owner, times and attributes
+//! and then keeps its own: `chmod`, `chown`, `set_xattr` and `set_timestamps`
+//! through one name don't show through the others, and a write through one
+//! name only moves that name's `modified` time. This is deliberate, as those
+//! are plain fields of `File` handed out by reference; code that needs them to
+//! agree across names has to set them on each.
+//!
+//! Symbolic links are directory entries rather than files; see `MemFs::symlink`.
+//!
+//! Quotas charge the shared data once, however many links hold it, and free it
+//! when the last counted link goes.
+
+use std::ops::Range;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::memfs::MemFs;
+use crate::quota::SharedCharge;
+use crate::state::FileState;
+use crate::storage::Storage;
+use crate::{File, FileError};
+
+/// A `Storage` shared by every hard link to the same data. It can't lend out
+/// one slice, so `view` needs a copy while other links exist.
+#[derive(Debug,Clone)]
+pub struct Linked {
+  data: Arc<RwLock<Box<dyn Storage>>>,
+  charge: Arc<Mutex<SharedCharge>>,
+}
+
+impl Linked {
+  pub fn new(data: Box<dyn Storage>) -> Linked {
+    Linked { data: Arc::new(RwLock::new(data)), charge: Arc::default() }
+  }
+
+  /// Files sharing this data, including this one.
+  pub fn count(&self) -> usize {
+    Arc::strong_count(&self.data)
+  }
+
+  /// Whether `other` shares this data.
+  pub fn same(&self, other: &Linked) -> bool {
+    Arc::ptr_eq(&self.data, &other.data)
+  }
+
+  /// Identifies the shared data while any link to it is alive.
+  pub fn id(&self) -> usize {
+    Arc::as_ptr(&self.data) as *const () as usize
+  }
+
+  /// What the data is charged against quotas, for all links at once.
+  pub(crate) fn charge(&self) -> MutexGuard<'_, SharedCharge> {
+    self.charge.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  /// Swaps in a new backend for every link at once, e.g. a sealed copy.
+  pub fn replace(&self, data: Box<dyn Storage>) {
+    *self.write() = data;
+  }
+
+  fn read(&self) -> RwLockReadGuard<'_, Box<dyn Storage>> {
+    self.data.read().unwrap_or_else(|e| e.into_inner())
+  }
+
+  fn write(&self) -> RwLockWriteGuard<'_, Box<dyn Storage>> {
+    self.data.write().unwrap_or_else(|e| e.into_inner())
+  }
+}
+
+impl Storage for Linked {
+  fn len(&self) -> usize {
+    self.read().len()
+  }
+
+  fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+    self.read().read_at(offset, buf)
+  }
+
+  fn write_at(&mut self, offset: usize, buf: &[u8]) -> Result<(), FileError> {
+    self.write().write_at(offset, buf)
+  }
+
+  fn insert(&mut self, offset: usize, buf: &[u8]) -> Result<(), FileError> {
+    self.write().insert(offset, buf)
+  }
+
+  fn remove(&mut self, range: Range<usize>) -> Result<(), FileError> {
+    self.write().remove(range)
+  }
+
+  fn resize(&mut self, len: usize) -> Result<(), FileError> {
+    self.write().resize(len)
+  }
+
+  fn stored_len(&self) -> usize {
+    self.read().stored_len()
+  }
+
+  fn next_data(&self, offset: usize) -> Option<usize> {
+    self.read().next_data(offset)
+  }
+
+  fn next_hole(&self, offset: usize) -> usize {
+    self.read().next_hole(offset)
+  }
+
+  /// The data's own identity if it is deduplicated too, so files sharing the
+  /// blob outside this set of links count it once with them.
+  fn shared_id(&self) -> Option<usize> {
+    self.read().shared_id().or(Some(self.id()))
+  }
+
+  fn as_linked(&self) -> Option<&Linked> {
+    Some(self)
+  }
+}
+
+impl File {
+  /// How many files share this one's data; 1 unless it has been hard linked.
+  pub fn link_count(&self) -> usize {
+    self.data.as_linked().map_or(1, Linked::count)
+  }
+
+  /// A new file named `name` sharing this one's data, starting closed with a
+  /// copy of its permissions, times and attributes. Only the data stays
+  /// shared; see the module docs.
+  pub fn hard_link(&mut self, name: &str) -> Result<File, FileError> {
+    if self.state == FileState::Deleted {
+      return Err(FileError::NotFound);
+    }
+    if self.data.as_linked().is_none() {
+      let data = std::mem::replace(&mut self.data, Box::new(Vec::new()));
+      self.data = Box::new(Linked::new(data));
+      self.share_charge();
+      // Writes through other links bypass this file's running hash, so it
+      // must never be resumed, even after the data stops being shared.
+      self.hasher.invalidate();
+    }
+    let shared = self.data.as_linked().cloned().ok_or(FileError::NotFound)?;
+    let mut link = File::new_with_storage(name, shared);
+    link.perms = self.perms.clone();
+    link.user = self.user.clone();
+    link.compression = self.compression;
+    link.encrypted = self.encrypted;
+    link.times = self.times;
+    link.xattrs = self.xattrs.clone();
+    Ok(link)
+  }
+
+  /// Puts `data` in place of the current backend, for every link if there are
+  /// several.
+  pub(crate) fn set_storage(&mut self, data: Box<dyn Storage>) {
+    match self.data.as_linked() {
+      Some(linked) => linked.replace(data),
+      None => self.data = data,
+    }
+  }
+
+  /// Puts `data` in place of the current backend for this file alone; other
+  /// links keep the old data, and the quota charge follows.
+  pub(crate) fn unshare_storage(&mut self, data: Box<dyn Storage>) {
+    if self.data.as_linked().is_some() {
+      self.release_charge();
+      self.data = data;
+      self.settle();
+    } else {
+      self.data = data;
+    }
+  }
+
+  /// Refuses changes that only make sense for the data as a whole while other
+  /// names share it.
+  pub(crate) fn ensure_unlinked(&self) -> Result<(), FileError> {
+    if self.link_count() > 1 {
+      return Err(FileError::HardLinked);
+    }
+    Ok(())
+  }
+}
+
+impl MemFs {
+  /// Makes `new` another name for the data of the file at `existing`; its
+  /// permissions, times and attributes start as copies and then go their own
+  /// way. Directories can't be hard linked.
+  pub fn link(&mut self, existing: &str, new: &str) -> Result<&mut File, FileError> {
+    // Linking changes the source's data, so everything `insert` could refuse
+    // is checked first. Linked data is already charged, so only the file
+    // count can run out.
+    self.free_name(new)?;
+    self.quota.check_file_room()?;
+    let name = new.trim_end_matches('/').rsplit('/').next().unwrap_or(new);
+    let link = self.file_mut(existing)?.hard_link(name)?;
+    self.insert(new, link)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::archive::{read_fs, write_fs};
+  use crate::compress::Compression;
+
+  fn append(fs: &mut MemFs, path: &str, data: &[u8]) {
+    let file = fs.file_mut(path).unwrap();
+    file.open_append().unwrap();
+    file.write(data).unwrap();
+    file.close().unwrap();
+  }
+
+  #[test]
+  fn hard_links_share_data() {
+    let mut fs = MemFs::new();
+    fs.insert("/a", File::new_with_data("a", b"one")).unwrap();
+    fs.file_mut("/a").unwrap().chmod(0o600).unwrap();
+    fs.link("/a", "/b").unwrap();
+    append(&mut fs, "/b", b" two");
+    assert_eq!(&*fs.file("/a").unwrap().contents(), b"one two");
+    assert_eq!(fs.metadata("/a").unwrap().links, 2);
+    assert_eq!(fs.file("/b").unwrap().perms.mode_string(), "-rw-------");
+    fs.remove("/a").unwrap();
+    assert_eq!(fs.metadata("/b").unwrap().links, 1);
+    assert_eq!(&*fs.file("/b").unwrap().contents(), b"one two");
+  }
+
+  #[test]
+  fn metadata_is_per_name() {
+    let mut fs = MemFs::new();
+    fs.insert("/a", File::new_with_data("a", b"one")).unwrap();
+    fs.link("/a", "/b").unwrap();
+    fs.file_mut("/b").unwrap().chmod(0o600).unwrap();
+    fs.file_mut("/b").unwrap().set_xattr("user.k", "v").unwrap();
+    let a = fs.file("/a").unwrap();
+    assert_eq!((a.perms.mode, a.xattr("user.k")), (0o644, None));
+  }
+
+  #[test]
+  fn link_errors() {
+    let mut fs = MemFs::new();
+    fs.mkdir("/d").unwrap();
+    fs.create("/a").unwrap();
+    fs.create("/b").unwrap();
+    assert_eq!(fs.link("/d", "/e").err(), Some(FileError::IsADirectory));
+    assert_eq!(fs.link("/a", "/b").err(), Some(FileError::AlreadyExists));
+    assert_eq!(fs.link("/missing", "/c").err(), Some(FileError::NotFound));
+    assert!(!fs.exists("/c"));
+  }
+
+  #[test]
+  fn failed_link_leaves_the_source_alone() {
+    let mut fs = MemFs::new();
+    fs.insert("/a", File::new_with_data("a", b"data")).unwrap();
+    assert_eq!(fs.link("/a", "/missing/b").err(), Some(FileError::NotFound));
+    fs.set_quota(None, Some(1));
+    assert_eq!(fs.link("/a", "/b").err(), Some(FileError::OutOfSpace));
+    let a = fs.file("/a").unwrap();
+    assert!(a.data.as_linked().is_none());
+    assert_eq!((a.link_count(), fs.usage().bytes, fs.usage().files), (1, 4, 1));
+  }
+
+  #[test]
+  fn whole_data_changes_refused_while_linked() {
+    let mut a = File::new_with_data("a", b"data");
+    let b = a.hard_link("b").unwrap();
+    assert_eq!(a.set_compression(Compression::Lz77), Err(FileError::HardLinked));
+    assert_eq!(a.make_contiguous(), Err(FileError::HardLinked));
+    assert_eq!(a.make_sparse(), Err(FileError::HardLinked));
+    drop(b);
+    a.set_compression(Compression::Lz77).unwrap();
+    assert_eq!(&*a.contents(), b"data");
+  }
+
+  #[test]
+  fn symlinks_resolve_and_loop() {
+    let mut fs = MemFs::new();
+    fs.mkdir("/d").unwrap();
+    fs.insert("/d/f", File::new_with_data("f", b"x")).unwrap();
+    fs.symlink("f", "/d/rel").unwrap();
+    fs.symlink("/d", "/abs").unwrap();
+    assert_eq!(&*fs.file("/abs/rel").unwrap().contents(), b"x");
+    assert_eq!(fs.real_path("/abs/rel"), Ok("/d/f".to_string()));
+    fs.symlink("/b", "/a").unwrap();
+    fs.symlink("/a", "/b").unwrap();
+    assert_eq!(fs.file("/a").err(), Some(FileError::SymlinkLoop));
+    fs.symlink("/nowhere", "/dangling").unwrap();
+    assert_eq!(fs.file("/dangling").err(), Some(FileError::NotFound));
+    fs.remove("/d/rel").unwrap();
+    assert!(fs.exists("/d/f"));
+  }
+
+  #[test]
+  fn archives_keep_links() {
+    let mut fs = MemFs::new();
+    fs.insert("/a", File::new_with_data("a", b"shared")).unwrap();
+    fs.link("/a", "/b").unwrap();
+    fs.symlink("a", "/s").unwrap();
+    let mut fs = read_fs(&write_fs(&fs, Vec::new()).unwrap()[..]).unwrap();
+    assert_eq!(fs.metadata("/b").unwrap().links, 2);
+    assert_eq!(fs.read_link("/s"), Ok("a"));
+    append(&mut fs, "/b", b"!");
+    assert_eq!(&*fs.file("/s").unwrap().contents(), b"shared!");
+  }
+}
@@ -0,0 +1,427 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+use crate::state::FileState;
+use crate::{File, FileError};
+
+type FileId = u64;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum LockKind {
+  Shared,
+  Exclusive,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum LockPolicy {
+  /// `with_file*` waits for conflicting locks like any other lock request.
+  Advisory,
+  /// `with_file*` is refused while another thread holds a conflicting lock.
+  Mandatory,
+}
+
+#[derive(Debug,Clone,Copy)]
+enum Wait {
+  Never,
+  Forever,
+  Until(Instant),
+}
+
+#[derive(Debug,Default)]
+struct Holders {
+  shared: HashSet<ThreadId>,
+  exclusive: Option<ThreadId>,
+}
+
+#[derive(Debug,Default)]
+struct Table {
+  next_id: FileId,
+  holders: HashMap<FileId, Holders>,
+  /// The file each blocked thread is waiting for, i.e. the wait-for graph.
+  waiting: HashMap<ThreadId, FileId>,
+}
+
+/// Tracks which threads hold or wait for locks on a set of shared files, so a
+/// lock request that would close a cycle fails with `FileError::Deadlock`.
+#[derive(Debug,Default)]
+pub struct LockManager {
+  table: Mutex<Table>,
+  released: Condvar,
+}
+
+/// A `File` shared between threads through an `Arc`, guarded by a `LockManager`.
+#[derive(Debug)]
+pub struct SharedFile {
+  id: FileId,
+  policy: LockPolicy,
+  file: RwLock<File>,
+  manager: Arc<LockManager>,
+}
+
+pub(crate) struct SharedGuard<'a> {
+  file: RwLockReadGuard<'a, File>,
+  _held: Held<'a>,
+}
+
+pub(crate) struct ExclusiveGuard<'a> {
+  file: RwLockWriteGuard<'a, File>,
+  _held: Held<'a>,
+}
+
+/// Releases a granted lock when dropped. Guards keep it after their borrow of
+/// the file, so the lock is only released once the borrow has ended and the
+/// next holder never waits on the `RwLock`.
+struct Held<'a>(&'a SharedFile);
+
+impl Holders {
+  fn holds(&self, owner: ThreadId) -> bool {
+    self.exclusive == Some(owner) || self.shared.contains(&owner)
+  }
+
+  fn admits(&self, kind: LockKind) -> bool {
+    match kind {
+      LockKind::Shared => self.exclusive.is_none(),
+      LockKind::Exclusive => self.exclusive.is_none() && self.shared.is_empty(),
+    }
+  }
+
+  fn others(&self, owner: ThreadId) -> impl Iterator<Item = ThreadId> + '_ {
+    self.shared.iter().copied().chain(self.exclusive).filter(move |t| *t != owner)
+  }
+}
+
+impl Table {
+  /// Whether making `owner` wait for `file` would let the wait-for graph loop back to it.
+  fn would_deadlock(&self, owner: ThreadId, file: FileId) -> bool {
+    let mut seen = HashSet::new();
+    let mut stack = vec![file];
+    while let Some(id) = stack.pop() {
+      if !seen.insert(id) {
+        continue;
+      }
+      let Some(holders) = self.holders.get(&id) else { continue };
+      for holder in holders.others(owner) {
+        if let Some(next) = self.waiting.get(&holder) {
+          stack.push(*next);
+        }
+      }
+      if id != file && holders.holds(owner) {
+        return true;
+      }
+    }
+    false
+  }
+}
+
+impl LockManager {
+  pub fn new() -> Arc<LockManager> {
+    Arc::new(LockManager::default())
+  }
+
+  /// Hands `file` over to this manager for sharing across threads.
+  pub fn share(self: &Arc<Self>, file: File, policy: LockPolicy) -> Arc<SharedFile> {
+    let mut table = self.table();
+    let id = table.next_id;
+    table.next_id += 1;
+    table.holders.insert(id, Holders::default());
+    Arc::new(SharedFile {
+      id,
+      policy,
+      file: RwLock::new(file),
+      manager: Arc::clone(self),
+    })
+  }
+
+  fn table(&self) -> MutexGuard<'_, Table> {
+    self.table.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  fn acquire(&self, file: FileId, kind: LockKind, wait: Wait) -> Result<(), FileError> {
+    let owner = thread::current().id();
+    let mut table = self.table();
+    if table.holders.entry(file).or_default().holds(owner) {
+      // A second guard on the same file from one thread could never be granted.
+      return Err(FileError::Deadlock);
+    }
+    loop {
+      let holders = table.holders.entry(file).or_default();
+      if holders.admits(kind) {
+        match kind {
+          LockKind::Shared => {
+            holders.shared.insert(owner);
+          }
+          LockKind::Exclusive => holders.exclusive = Some(owner),
+        }
+        table.waiting.remove(&owner);
+        return Ok(());
+      }
+
+      let timeout = match wait {
+        Wait::Never => return Err(FileError::Locked),
+        Wait::Forever => None,
+        Wait::Until(deadline) => match deadline.checked_duration_since(Instant::now()) {
+          Some(left) if !left.is_zero() => Some(left),
+          _ => {
+            table.waiting.remove(&owner);
+            return Err(FileError::TimedOut);
+          }
+        },
+      };
+      if table.would_deadlock(owner, file) {
+        table.waiting.remove(&owner);
+        return Err(FileError::Deadlock);
+      }
+      table.waiting.insert(owner, file);
+      table = match timeout {
+        None => self.released.wait(table).unwrap_or_else(|e| e.into_inner()),
+        Some(left) => self.released.wait_timeout(table, left).unwrap_or_else(|e| e.into_inner()).0,
+      };
+    }
+  }
+
+  fn release(&self, file: FileId) {
+    let owner = thread::current().id();
+    let mut table = self.table();
+    if let Some(holders) = table.holders.get_mut(&file) {
+      holders.shared.remove(&owner);
+      if holders.exclusive == Some(owner) {
+        holders.exclusive = None;
+      }
+    }
+    self.released.notify_all();
+  }
+
+  /// Stops tracking a file that is no longer shared.
+  fn forget(&self, file: FileId) {
+    self.table().holders.remove(&file);
+  }
+}
+
+impl SharedFile {
+  pub fn policy(&self) -> LockPolicy {
+    self.policy
+  }
+
+  /// Blocks until a shared lock is granted, or fails with `Deadlock`.
+  pub fn lock_shared(&self) -> Result<SharedGuard<'_>, FileError> {
+    self.shared_guard(Wait::Forever)
+  }
+
+  /// Blocks until an exclusive lock is granted, or fails with `Deadlock`.
+  pub fn lock_exclusive(&self) -> Result<ExclusiveGuard<'_>, FileError> {
+    self.exclusive_guard(Wait::Forever)
+  }
+
+  /// Fails with `Locked` instead of waiting.
+  pub fn try_lock_shared(&self) -> Result<SharedGuard<'_>, FileError> {
+    self.shared_guard(Wait::Never)
+  }
+
+  /// Fails with `Locked` instead of waiting.
+  pub fn try_lock(&self) -> Result<ExclusiveGuard<'_>, FileError> {
+    self.exclusive_guard(Wait::Never)
+  }
+
+  pub fn lock_shared_timeout(&self, timeout: Duration) -> Result<SharedGuard<'_>, FileError> {
+    self.shared_guard(Wait::Until(Instant::now() + timeout))
+  }
+
+  pub fn lock_exclusive_timeout(&self, timeout: Duration) -> Result<ExclusiveGuard<'_>, FileError> {
+    self.exclusive_guard(Wait::Until(Instant::now() + timeout))
+  }
+
+  /// Reads the file under a shared lock held just for `f`; refused with `Locked`
+  /// under `Mandatory` while another thread holds it exclusively.
+  pub fn with_file<R>(&self, f: impl FnOnce(&File) -> R) -> Result<R, FileError> {
+    let guard = self.read_guard(self.unlocked_wait())?;
+    Ok(f(&guard))
+  }
+
+  /// Mutates the file under an exclusive lock held just for `f`; refused with
+  /// `Locked` under `Mandatory` while any other thread holds a lock.
+  pub fn with_file_mut<R>(&self, f: impl FnOnce(&mut File) -> R) -> Result<R, FileError> {
+    let mut guard = self.write_guard(self.unlocked_wait())?;
+    Ok(f(&mut guard))
+  }
+
+  /// How long `with_file*` waits for a conflicting lock.
+  fn unlocked_wait(&self) -> Wait {
+    match self.policy {
+      LockPolicy::Advisory => Wait::Forever,
+      LockPolicy::Mandatory => Wait::Never,
+    }
+  }
+
+  fn shared_guard(&self, wait: Wait) -> Result<SharedGuard<'_>, FileError> {
+    let guard = self.read_guard(wait)?;
+    if guard.file.state == FileState::Deleted {
+      return Err(FileError::NotFound);
+    }
+    Ok(guard)
+  }
+
+  fn exclusive_guard(&self, wait: Wait) -> Result<ExclusiveGuard<'_>, FileError> {
+    let guard = self.write_guard(wait)?;
+    if guard.file.state == FileState::Deleted {
+      return Err(FileError::NotFound);
+    }
+    Ok(guard)
+  }
+
+  /// A shared guard on the file whatever its state.
+  fn read_guard(&self, wait: Wait) -> Result<SharedGuard<'_>, FileError> {
+    self.manager.acquire(self.id, LockKind::Shared, wait)?;
+    let held = Held(self);
+    Ok(SharedGuard {
+      file: self.file.read().unwrap_or_else(|e| e.into_inner()),
+      _held: held,
+    })
+  }
+
+  /// An exclusive guard on the file whatever its state. A closed file is
+  /// `Locked` until the guard goes.
+  fn write_guard(&self, wait: Wait) -> Result<ExclusiveGuard<'_>, FileError> {
+    self.manager.acquire(self.id, LockKind::Exclusive, wait)?;
+    let held = Held(self);
+    let mut file = self.file.write().unwrap_or_else(|e| e.into_inner());
+    if file.state == FileState::Closed {
+      file.lock()?;
+    }
+    Ok(ExclusiveGuard { file, _held: held })
+  }
+}
+
+impl Drop for SharedFile {
+  fn drop(&mut self) {
+    self.manager.forget(self.id);
+  }
+}
+
+/// Leaves `Locked` before the file is handed to the next holder.
+impl Drop for ExclusiveGuard<'_> {
+  fn drop(&mut self) {
+    if self.file.state == FileState::Locked {
+      let _ = self.file.unlock();
+    }
+  }
+}
+
+impl Drop for Held<'_> {
+  fn drop(&mut self) {
+    self.0.manager.release(self.0.id);
+  }
+}
+
+impl Deref for SharedGuard<'_> {
+  type Target = File;
+
+  fn deref(&self) -> &File {
+    &self.file
+  }
+}
+
+impl Deref for ExclusiveGuard<'_> {
+  type Target = File;
+
+  fn deref(&self) -> &File {
+    &self.file
+  }
+}
+
+impl DerefMut for ExclusiveGuard<'_> {
+  fn deref_mut(&mut self) -> &mut File {
+    &mut self.file
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Barrier;
+
+  #[test]
+  fn exclusive_excludes_and_shared_shares() {
+    let locks = LockManager::new();
+    let f = locks.share(File::new("a"), LockPolicy::Advisory);
+    let other = Arc::clone(&f);
+    let guard = f.lock_shared().unwrap();
+    let seen = thread::spawn(move || {
+      let shared = other.try_lock_shared().map(|g| g.name.clone());
+      let exclusive = other.try_lock().err();
+      let timed = other.lock_exclusive_timeout(Duration::from_millis(20)).err();
+      (shared, exclusive, timed)
+    }).join().unwrap();
+    assert_eq!(seen, (Ok("a".to_string()), Some(FileError::Locked), Some(FileError::TimedOut)));
+    drop(guard);
+  }
+
+  #[test]
+  fn exclusive_locks_drive_the_locked_state() {
+    let locks = LockManager::new();
+    let f = locks.share(File::new("a"), LockPolicy::Advisory);
+    {
+      let guard = f.lock_exclusive().unwrap();
+      assert_eq!(guard.state, FileState::Locked);
+    }
+    assert_eq!(f.lock_shared().unwrap().state, FileState::Closed);
+    f.with_file_mut(|file| file.open().unwrap()).unwrap();
+    f.with_file_mut(|file| assert_eq!(file.state, FileState::Open)).unwrap();
+    let log = f.with_file(|file| file.transitions().map(|t| t.to_string()).collect::<Vec<_>>()).unwrap();
+    assert_eq!(log, [
+      "CLOSED --lock--> LOCKED",
+      "LOCKED --unlock--> CLOSED",
+      "CLOSED --lock--> LOCKED",
+      "LOCKED --open--> OPEN",
+    ]);
+  }
+
+  #[test]
+  fn relocking_from_the_same_thread_is_a_deadlock() {
+    let locks = LockManager::new();
+    let f = locks.share(File::new("a"), LockPolicy::Advisory);
+    let _guard = f.lock_exclusive().unwrap();
+    assert_eq!(f.lock_shared().err(), Some(FileError::Deadlock));
+    assert_eq!(f.with_file(|_| ()), Err(FileError::Deadlock));
+  }
+
+  #[test]
+  fn cycles_through_with_file_are_reported() {
+    let locks = LockManager::new();
+    let a = locks.share(File::new("a"), LockPolicy::Advisory);
+    let b = locks.share(File::new("b"), LockPolicy::Advisory);
+    let barrier = Arc::new(Barrier::new(2));
+    let handles: Vec<_> = [(a.clone(), b.clone()), (b, a)].into_iter().map(|(first, second)| {
+      let barrier = barrier.clone();
+      thread::spawn(move || {
+        let _held = first.lock_exclusive().unwrap();
+        barrier.wait();
+        second.with_file_mut(|_| ())
+      })
+    }).collect();
+    let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    assert!(results.contains(&Err(FileError::Deadlock)));
+    assert!(results.contains(&Ok(())));
+  }
+
+  #[test]
+  fn mandatory_refuses_instead_of_waiting() {
+    let locks = LockManager::new();
+    let f = locks.share(File::new("a"), LockPolicy::Mandatory);
+    let other = Arc::clone(&f);
+    let _guard = f.lock_shared().unwrap();
+    let seen = thread::spawn(move || (other.with_file(|f| f.len()), other.with_file_mut(|_| ()))).join().unwrap();
+    assert_eq!(seen, (Ok(0), Err(FileError::Locked)));
+  }
+
+  #[test]
+  fn dropped_files_are_forgotten() {
+    let locks = LockManager::new();
+    let f = locks.share(File::new("a"), LockPolicy::Advisory);
+    f.with_file_mut(|f| f.open()).unwrap().unwrap();
+    drop(f);
+    assert!(locks.table().holders.is_empty());
+  }
+}
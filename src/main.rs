@@ -1,34 +1,102 @@
 #![allow(dead_code)]
 
+mod aio;
+mod archive;
+mod cli;
+mod compress;
+mod crypt;
+mod error;
+mod fault;
+mod format;
+mod hash;
+mod journal;
+mod link;
+mod lock;
+mod memfs;
+mod perm;
+mod persist;
+mod quota;
+mod snapshot;
+mod sparse;
+mod state;
+mod storage;
+mod text;
+mod time;
+mod view;
+mod watch;
+mod xattr;
+
+use std::borrow::Cow;
+use std::collections::VecDeque;
 use std::fmt;
 use std::fmt::{Display};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::time::SystemTime;
 
-#[derive(Debug,PartialEq)]
-enum FileState {
-  Open,
-  Closed,
-}
+use compress::{Compressed, Compression};
+use error::FileError;
+use fault::Faults;
+use hash::ContentHasher;
+use journal::{Journal, Op};
+use perm::{Access, Permissions, User};
+use quota::Limits;
+use snapshot::History;
+use state::{FileEvent, FileState, Transition};
+use storage::{check_range, Storage};
+use time::Timestamps;
+use watch::{WatchEvent, Watchers};
+use xattr::Xattrs;
+
+/// How many state transitions a `File` remembers for debugging.
+const TRANSITION_LOG_LEN: usize = 32;
 
 #[derive(Debug)]
 struct File {
   name: String,
-  data: Vec<u8>,
+  data: Box<dyn Storage>,
   state: FileState,
-}
-
-impl Display for FileState {
-   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-     match self {
-         FileState::Open => write!(f, "OPEN"),
-         FileState::Closed => write!(f, "CLOSED"),
-     }
-   }
+  pos: usize,
+  transitions: VecDeque<Transition>,
+  /// The on-disk file this one mirrors, if any.
+  backing: Option<PathBuf>,
+  /// Set by every mutation since the last load or flush.
+  dirty: bool,
+  /// Write-ahead log for the backing file; see `journal`.
+  journal: Option<Journal>,
+  perms: Permissions,
+  /// Who opens, reads and writes through this handle.
+  user: User,
+  history: History,
+  compression: Compression,
+  /// `data` holds ciphertext; see `crypt`.
+  encrypted: bool,
+  watchers: Watchers,
+  limits: Limits,
+  hasher: ContentHasher,
+  times: Timestamps,
+  xattrs: Xattrs,
+  /// Injected failures for testing; see `fault`.
+  faults: Option<Faults>,
 }
 
 impl Display for File {
+   /// `{:#}` gives the multi-line long form; see `format`.
    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-      write!(f, "<{} ({})>",
-             self.name, self.state)
+      if f.alternate() {
+        return self.fmt_long(f);
+      }
+      write!(f, "{} <{} ({})>",
+             self.perms, self.name, self.state)?;
+      if self.compression != Compression::None {
+        write!(f, " [{} {}/{} bytes]", self.compression, self.stored_len(), self.len())?;
+      }
+      if self.encrypted {
+        write!(f, " [encrypted]")?;
+      }
+      Ok(())
    }
 }
 
@@ -36,15 +104,647 @@ impl File {
   fn new(name: &str) -> File {
     File {
         name: String::from(name),
-        data: Vec::new(),
+        data: Box::new(Vec::new()),
         state: FileState::Closed,
+        pos: 0,
+        transitions: VecDeque::new(),
+        backing: None,
+        dirty: false,
+        journal: None,
+        perms: Permissions::default(),
+        user: User::default(),
+        history: History::default(),
+        compression: Compression::None,
+        encrypted: false,
+        watchers: Watchers::default(),
+        limits: Limits::default(),
+        hasher: ContentHasher::default(),
+        times: Timestamps::default(),
+        xattrs: Xattrs::new(),
+        faults: None,
+    }
+  }
+
+  fn new_with_data(name: &str, data: &[u8]) -> File {
+    File::new_with_storage(name, data.to_vec())
+  }
+
+  /// Creates a file on a specific backend, e.g. `storage::Rope` for large payloads.
+  fn new_with_storage<S: Storage + 'static>(name: &str, storage: S) -> File {
+    let mut f = File::new(name);
+    f.data = Box::new(storage);
+    f
+  }
+
+  /// Repacks the data under `policy`; `Compression::None` unpacks compressed data
+  /// into a plain `Vec` and leaves other backends alone. Ciphertext doesn't
+  /// compress, and other hard links would keep a stale `compression`, so
+  /// encrypted and linked files are refused.
+  fn set_compression(&mut self, policy: Compression) -> Result<(), FileError> {
+    self.ensure_plaintext()?;
+    self.ensure_unlinked()?;
+    if policy == self.compression {
+      // Repacking would turn sparse or rope data into a plain `Vec`.
+      return Ok(());
+    }
+    let data = self.data.contents().into_owned();
+    self.unshare_storage(match policy {
+      Compression::None => Box::new(data),
+      Compression::Lz77 => Box::new(Compressed::from_bytes(&data)),
+    });
+    self.compression = policy;
+    Ok(())
+  }
+
+  /// Bytes the data occupies in memory, as opposed to its logical `len`.
+  fn stored_len(&self) -> usize {
+    self.data.stored_len()
+  }
+
+  /// The whole data; copied only if the backend is not contiguous.
+  fn contents(&self) -> Cow<'_, [u8]> {
+    self.data.contents()
+  }
+
+  /// Opens for reading and writing.
+  fn open(&mut self) -> Result<(), FileError> {
+    self.check_access(Access::Read)?;
+    self.check_access(Access::Write)?;
+    self.transition(FileEvent::Open)?;
+    self.pos = 0;
+    Ok(())
+  }
+
+  fn open_read(&mut self) -> Result<(), FileError> {
+    self.check_access(Access::Read)?;
+    self.transition(FileEvent::OpenRead)?;
+    self.pos = 0;
+    Ok(())
+  }
+
+  fn open_write(&mut self) -> Result<(), FileError> {
+    self.check_access(Access::Write)?;
+    self.transition(FileEvent::OpenWrite)?;
+    self.pos = 0;
+    Ok(())
+  }
+
+  fn open_append(&mut self) -> Result<(), FileError> {
+    self.check_access(Access::Write)?;
+    self.transition(FileEvent::OpenAppend)?;
+    self.pos = self.data.len();
+    Ok(())
+  }
+
+  fn close(&mut self) -> Result<(), FileError> {
+    self.transition(FileEvent::Close)
+  }
+
+  /// Marks a closed file as held under an exclusive lock; see `lock`.
+  fn lock(&mut self) -> Result<(), FileError> {
+    self.transition(FileEvent::Lock)
+  }
+
+  fn unlock(&mut self) -> Result<(), FileError> {
+    self.transition(FileEvent::Unlock)
+  }
+
+  /// Marks the file deleted and drops its data; no event is accepted afterwards.
+  /// A backing file on disk is left alone.
+  fn delete(&mut self) -> Result<(), FileError> {
+    self.transition(FileEvent::Delete)?;
+    self.unshare_storage(Box::new(Vec::new()));
+    self.compression = Compression::None;
+    self.encrypted = false;
+    self.pos = 0;
+    self.dirty = false;
+    self.settle();
+    self.hasher.invalidate();
+    Ok(())
+  }
+
+  /// Runs `event` through the transition table, logging it if the state changed.
+  fn transition(&mut self, event: FileEvent) -> Result<(), FileError> {
+    let from = self.state;
+    let to = from.apply(event)?;
+    if to != from {
+      if self.transitions.len() == TRANSITION_LOG_LEN {
+        self.transitions.pop_front();
+      }
+      self.transitions.push_back(Transition { from, event, to });
+      self.state = to;
+      match event {
+        FileEvent::Open | FileEvent::OpenRead | FileEvent::OpenWrite | FileEvent::OpenAppend => {
+          self.notify(WatchEvent::Opened)
+        }
+        FileEvent::Close => self.notify(WatchEvent::Closed),
+        FileEvent::Delete => self.notify(WatchEvent::Deleted),
+        _ => {}
+      }
+    }
+    Ok(())
+  }
+
+  /// Records that `len` bytes at `offset` changed.
+  fn modified(&mut self, offset: usize, len: usize) {
+    self.dirty = true;
+    self.times.modified = SystemTime::now();
+    self.settle();
+    self.hasher.update(&*self.data, offset);
+    self.notify(WatchEvent::Written { offset, len });
+  }
+
+  /// The most recent state transitions, oldest first.
+  fn transitions(&self) -> impl Iterator<Item = &Transition> {
+    self.transitions.iter()
+  }
+
+  fn len(&self) -> usize {
+    self.data.len()
+  }
+
+  fn is_empty(&self) -> bool {
+    self.data.is_empty()
+  }
+
+  /// Reads from the current position, returning 0 at end of file.
+  fn read(&mut self, buf: &mut [u8]) -> Result<usize, FileError> {
+    self.transition(FileEvent::Read)?;
+    self.check_access(Access::Read)?;
+    self.ensure_plaintext()?;
+    let buf = self.inject_read_faults(buf);
+    let n = self.data.read_at(self.pos, buf);
+    self.corrupt_read(&mut buf[..n]);
+    self.pos += n;
+    self.times.accessed = SystemTime::now();
+    Ok(n)
+  }
+
+  /// Writes at the current position, zero-filling any gap left by a seek past the end.
+  /// Files opened for append always write at the end.
+  fn write(&mut self, buf: &[u8]) -> Result<usize, FileError> {
+    self.transition(FileEvent::Write)?;
+    self.check_access(Access::Write)?;
+    self.ensure_plaintext()?;
+    self.inject_write_fault()?;
+    if self.state == FileState::OpenAppend {
+      self.pos = self.data.len();
+    }
+    let end = self.pos.checked_add(buf.len()).ok_or(FileError::OutOfSpace)?;
+    self.reserved(end, |file| {
+      file.log(Op::Write { offset: file.pos, data: buf })?;
+      file.data.write_at(file.pos, buf)
+    })?;
+    self.modified(self.pos, buf.len());
+    self.pos += buf.len();
+    Ok(buf.len())
+  }
+
+  /// Inserts at `offset`, shifting the rest of the data along.
+  fn insert_at(&mut self, offset: usize, buf: &[u8]) -> Result<(), FileError> {
+    self.transition(FileEvent::Write)?;
+    self.check_access(Access::Write)?;
+    self.ensure_plaintext()?;
+    self.inject_write_fault()?;
+    // Checked before anything is reserved or logged for an op that can't apply.
+    if offset > self.data.len() {
+      return Err(FileError::InvalidSeek);
+    }
+    self.reserved(self.data.len() + buf.len(), |file| {
+      file.log(Op::Insert { offset, data: buf })?;
+      file.data.insert(offset, buf)
+    })?;
+    self.modified(offset, self.data.len() - offset);
+    Ok(())
+  }
+
+  /// Cuts `range` out of the data, shifting the rest back.
+  fn remove_range(&mut self, range: Range<usize>) -> Result<(), FileError> {
+    self.transition(FileEvent::Write)?;
+    self.check_access(Access::Write)?;
+    self.ensure_plaintext()?;
+    self.inject_write_fault()?;
+    check_range(&range, self.data.len())?;
+    self.log(Op::Remove(range.clone()))?;
+    self.data.remove(range.clone())?;
+    self.modified(range.start, self.data.len() + range.len() - range.start);
+    Ok(())
+  }
+
+  fn seek(&mut self, pos: SeekFrom) -> Result<u64, FileError> {
+    self.transition(FileEvent::Seek)?;
+    let (base, offset) = match pos {
+      SeekFrom::Start(n) => (0, n as i64),
+      SeekFrom::End(n) => (self.data.len() as i64, n),
+      SeekFrom::Current(n) => (self.pos as i64, n),
+    };
+    let new_pos = base.checked_add(offset).ok_or(FileError::InvalidSeek)?;
+    if new_pos < 0 {
+      return Err(FileError::InvalidSeek);
+    }
+    self.pos = new_pos as usize;
+    Ok(self.pos as u64)
+  }
+
+  /// Shrinks or zero-extends the data to `len`; the position is left untouched.
+  fn truncate(&mut self, len: usize) -> Result<(), FileError> {
+    self.transition(FileEvent::Write)?;
+    self.check_access(Access::Write)?;
+    self.ensure_plaintext()?;
+    self.inject_write_fault()?;
+    let old_len = self.data.len();
+    self.reserved(len, |file| {
+      file.log(Op::Resize(len))?;
+      file.data.resize(len)
+    })?;
+    self.modified(len.min(old_len), len.abs_diff(old_len));
+    Ok(())
+  }
+
+  /// Swaps in new data wholesale, as `rollback` does. Needs no open handle,
+  /// but otherwise passes the same checks as `write`.
+  fn replace_data(&mut self, data: &[u8]) -> Result<(), FileError> {
+    if self.state == FileState::Deleted {
+      return Err(FileError::NotFound);
+    }
+    self.check_access(Access::Write)?;
+    self.ensure_plaintext()?;
+    self.inject_write_fault()?;
+    self.reserved(data.len(), |file| {
+      file.log(Op::Replace(data))?;
+      file.data.resize(0)?;
+      file.data.write_at(0, data)
+    })?;
+    self.modified(0, data.len());
+    Ok(())
+  }
+
+  /// Switches the identity used for permission checks; takes effect on the next operation.
+  fn set_user(&mut self, user: User) {
+    self.user = user;
+  }
+
+  fn permissions(&self) -> &Permissions {
+    &self.perms
+  }
+
+  /// Changes the mode bits; only the owner or root may do this.
+  fn chmod(&mut self, mode: u16) -> Result<(), FileError> {
+    if !self.user.is_root() && self.user.name != self.perms.owner {
+      return Err(FileError::PermissionDenied);
+    }
+    self.perms.mode = mode & 0o777;
+    Ok(())
+  }
+
+  /// Changes owner and group; only root may do this.
+  fn chown(&mut self, owner: &str, group: &str) -> Result<(), FileError> {
+    if !self.user.is_root() {
+      return Err(FileError::PermissionDenied);
+    }
+    self.perms.owner = String::from(owner);
+    self.perms.group = String::from(group);
+    Ok(())
+  }
+
+  fn check_access(&self, access: Access) -> Result<(), FileError> {
+    if self.perms.allows(&self.user, access) {
+      Ok(())
+    } else {
+      Err(FileError::PermissionDenied)
+    }
+  }
+
+  fn ensure_plaintext(&self) -> Result<(), FileError> {
+    if self.encrypted {
+      Err(FileError::Encrypted)
+    } else {
+      Ok(())
+    }
+  }
+
+  fn ensure_open(&self) -> Result<(), FileError> {
+    if self.state.is_open() {
+      Ok(())
+    } else {
+      Err(FileError::NotOpen)
     }
   }
 }
 
+impl Read for File {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    File::read(self, buf).map_err(io::Error::from)
+  }
+}
+
+impl Write for File {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    File::write(self, buf).map_err(io::Error::from)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    File::flush(self).map_err(io::Error::from)
+  }
+}
+
+impl Seek for File {
+  fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+    File::seek(self, pos).map_err(io::Error::from)
+  }
+}
+
 fn main() {
-  let f6: File = File::new("f6.txt");
+  let args: Vec<String> = std::env::args().skip(1).collect();
+  if args.first().is_some_and(|a| a == "demo") {
+    demo();
+    return;
+  }
+  if let Err(e) = cli::run(&args) {
+    eprintln!("world_hello: {}", e);
+    std::process::exit(e.exit_code());
+  }
+}
+
+/// A tour of the features, run by the `demo` command.
+fn demo() {
+  let mut f6: File = File::new("f6.txt");
   //...
   println!("{:?}", f6);
   println!("{}", f6);
-}
\ No newline at end of file
+
+  let mut buffer = vec![0; 5];
+  if let Err(e) = f6.read(&mut buffer) {
+    println!("read failed: {}", e);
+  }
+
+  f6.open().unwrap();
+  f6.write_all(b"hello, world").unwrap();
+  f6.seek(SeekFrom::Start(7)).unwrap();
+  let mut text = String::new();
+  f6.read_to_string(&mut text).unwrap();
+  println!("{} {:?}", f6, text);
+  f6.close().unwrap();
+
+  f6.open_read().unwrap();
+  if let Err(e) = f6.write(b"!") {
+    println!("write failed: {}", e);
+  }
+  f6.close().unwrap();
+  for t in f6.transitions() {
+    println!("{}", t);
+  }
+
+  let disk_path = std::env::temp_dir().join("f6.txt");
+  f6.sync_to(&disk_path).unwrap();
+  let loaded = File::load(&disk_path).unwrap();
+  println!("{} dirty={} {:?}", loaded, loaded.is_dirty(), String::from_utf8_lossy(&loaded.contents()));
+
+  f6.chown("alice", "staff").unwrap();
+  f6.chmod(0o640).unwrap();
+  f6.set_user(User::new("bob", &["staff"]));
+  if let Err(e) = f6.open() {
+    println!("{} as bob: {}", f6, e);
+  }
+  f6.set_user(User::root());
+
+  let v0 = f6.snapshot("before edit");
+  f6.open().unwrap();
+  f6.seek(SeekFrom::Start(7)).unwrap();
+  f6.write_all(b"there").unwrap();
+  f6.close().unwrap();
+  let v1 = f6.snapshot("after edit");
+  println!("{:?}", f6.diff(v0, v1).unwrap());
+  f6.rollback(v0).unwrap();
+  for v in f6.versions() {
+    println!("{}", v);
+  }
+
+  let mut big = File::new_with_storage("big.bin", storage::Rope::from_bytes(&vec![b'.'; 1 << 20]));
+  big.open().unwrap();
+  big.insert_at(512 * 1024, b"middle").unwrap();
+  big.remove_range(0..10).unwrap();
+  println!("{} {} bytes {:?}", big, big.len(), big.data);
+  big.close().unwrap();
+  big.set_compression(Compression::Lz77).unwrap();
+  println!("{}", big);
+
+  let header = File::new_with_data("header.bin", &[0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0x10, 0x20, 0x30, 0x40]);
+  let view = header.view().unwrap();
+  println!("magic {:#010x} word {:#x} records {} past end {:?}",
+           view.u32_be(0).unwrap(), view.u32_le(8).unwrap(), view.records(4).count(), view.u64_le(8));
+
+  println!("{:#}", header);
+  println!("{}", header.json());
+  let mut hidden = File::new_with_data("hidden.bin", b"top secret");
+  hidden.perms = perm::Permissions::new("alice", "staff", 0o600);
+  hidden.set_user(perm::User::new("bob", &["users"]));
+  print!("{}", format::Table::of_files([&header, &hidden]));
+
+  let notes = File::new_with_data("notes.txt", b"first\r\nbad \xff line\nlast");
+  for line in notes.lines().unwrap() {
+    println!("{:?}", line);
+  }
+  let csv = File::new_with_data("people.csv", b"name,quote\nada,\"said \"\"hi\"\", twice\"\nbob,\"unterminated\n");
+  for record in csv.csv_records().unwrap() {
+    println!("{:?}", record);
+  }
+  println!("fields {:?}", csv.split(b',').unwrap().count());
+
+  let key = crypt::FileKey::generate();
+  let mut secret = File::new_with_data("secret.txt", b"hunter2");
+  secret.encrypt(&key).unwrap();
+  println!("{} {:?}", secret, secret.read_encrypted(&key).map(String::from_utf8));
+  println!("wrong key: {:?}", secret.read_encrypted(&crypt::FileKey::generate()));
+
+  let mut fs = memfs::MemFs::new();
+  fs.mkdir_all("/docs/drafts").unwrap();
+  let (_, events) = fs.watch("/docs").unwrap();
+  fs.create("/docs/readme.txt").unwrap();
+  fs.create("/docs/drafts/todo.txt").unwrap();
+  fs.rename("/docs/drafts/todo.txt", "/docs/todo.txt").unwrap();
+  for entry in fs.list_dir("/docs").unwrap() {
+    println!("{}", entry);
+  }
+  println!("{:?}", fs.metadata("/docs/todo.txt").unwrap());
+  fs.file_mut("/docs/todo.txt").unwrap().open_append().unwrap();
+  fs.file_mut("/docs/todo.txt").unwrap().write_all(b"buy milk").unwrap();
+  fs.file_mut("/docs/todo.txt").unwrap().close().unwrap();
+  fs.remove("/docs/readme.txt").unwrap();
+  for n in events.try_iter() {
+    println!("{:?}", n);
+  }
+
+  fs.set_quota(Some(64), Some(4));
+  let log = fs.create("/docs/log.txt").unwrap();
+  log.set_max_size(Some(40));
+  log.open_append().unwrap();
+  println!("log: {:?} then {:?}", log.write(&[b'x'; 40]), log.write(b"!"));
+  log.close().unwrap();
+  let big = File::new_with_data("big.bin", &[0; 32]);
+  println!("insert over quota: {:?}", fs.insert("/docs/big.bin", big).map(|_| ()));
+  println!("usage: {} ({:?} free); /docs holds {} bytes",
+           fs.usage(), fs.usage().bytes_free(), fs.disk_usage("/docs").unwrap());
+
+  fs.set_quota(None, None);
+  for name in ["a", "b", "c"] {
+    fs.insert(&format!("/docs/copy-{}.txt", name), File::new_with_data(name, b"same old contents")).unwrap();
+  }
+  println!("dedup: {}; store holds {}", fs.dedup(), fs.content_store().len());
+  let copy = fs.file_mut("/docs/copy-a.txt").unwrap();
+  copy.open_append().unwrap();
+  copy.write_all(b", appended").unwrap();
+  println!("{} incremental hash matches: {}",
+           copy.content_hash(), copy.content_hash() == hash::ContentHash::of(&copy.contents()));
+
+  let since = std::time::SystemTime::now() - std::time::Duration::from_secs(60);
+  fs.file_mut("/docs/todo.txt").unwrap().set_xattr("user.owner-team", "infra").unwrap();
+  fs.file_mut("/docs/log.txt").unwrap().set_xattr("user.owner-team", "web").unwrap();
+  let infra: Vec<_> = fs.find_by_xattr("user.owner-team", Some("infra")).into_iter().map(|(p, _)| p).collect();
+  println!("infra files {:?}; {} modified in the last minute; reserved key: {:?}",
+           infra, fs.modified_in(since..).len(), fs.file_mut("/docs/todo.txt").unwrap().set_xattr("user.memfs.state", "x"));
+
+  fs.link("/docs/todo.txt", "/docs/todo-link.txt").unwrap();
+  let todo_link = fs.file_mut("/docs/todo-link.txt").unwrap();
+  todo_link.open_append().unwrap();
+  todo_link.write_all(b" (via link)").unwrap();
+  todo_link.close().unwrap();
+  fs.symlink("todo.txt", "/docs/latest").unwrap();
+  fs.symlink("/docs", "/shortcut").unwrap();
+  fs.symlink("loop-b", "/loop-a").unwrap();
+  fs.symlink("loop-a", "/loop-b").unwrap();
+  println!("{:?} has {} links, read via /shortcut/latest: {:?}; loop: {}",
+           fs.read_link("/docs/latest"), fs.metadata("/docs/todo.txt").unwrap().links,
+           String::from_utf8_lossy(&fs.file("/shortcut/latest").unwrap().contents()),
+           fs.file("/loop-a/x").unwrap_err());
+
+  let mut disk = File::new_with_storage("disk.img", sparse::Sparse::new());
+  disk.open().unwrap();
+  disk.write_all(b"boot").unwrap();
+  disk.seek(SeekFrom::Start(1 << 20)).unwrap();
+  disk.write_all(b"superblock").unwrap();
+  disk.truncate(4 << 20).unwrap();
+  let data_at = disk.seek_data(4).unwrap();
+  println!("{} bytes, {} allocated; hole at {}, data again at {}, then {:?}",
+           disk.len(), disk.stored_len(), disk.seek_hole(0).unwrap(), data_at, disk.seek_data(data_at + 10));
+  disk.close().unwrap();
+
+  let flaky_run = |seed| {
+    let mut flaky = File::new("flaky.bin");
+    flaky.set_faults(Some(fault::FaultPlan {
+      seed, fail_nth_write: Some(3), short_read_rate: 0.5, corrupt_rate: 0.5, ..Default::default()
+    }));
+    flaky.open().unwrap();
+    let writes: Vec<_> = (0..4).map(|_| flaky.write(b"0123456789").is_ok()).collect();
+    flaky.seek(SeekFrom::Start(0)).unwrap();
+    let mut back = Vec::new();
+    let read = flaky.read_to_end(&mut back).map(|_| back.len());
+    (writes, read, back == b"012345678901234567890123456789", flaky.faults().unwrap().log().to_vec())
+  };
+  let (writes, read, intact, log) = flaky_run(8);
+  println!("writes ok {:?}, read {:?} intact {}, same again: {}; faults: {:?}",
+           writes, read, intact, flaky_run(8).3 == log, log);
+  fs.set_faults(Some(fault::FaultPlan { seed: 1, write_failure_rate: 1.0, ..Default::default() }));
+  let todo = fs.file_mut("/docs/todo.txt").unwrap();
+  todo.open_append().unwrap();
+  println!("store-wide: {}", todo.write(b"!").unwrap_err());
+  todo.close().unwrap();
+  fs.set_faults(None);
+
+  fs.insert("/docs/f6.txt", f6).unwrap();
+  let packed = archive::write_fs(&fs, Vec::new()).unwrap();
+  let unpacked = archive::read_fs(packed.as_slice()).unwrap();
+  for (path, entry) in unpacked.walk() {
+    println!("{} {}", path, entry);
+  }
+  println!("{:#}", unpacked.file("/docs/todo.txt").unwrap());
+
+  // Generic over the tokio traits, as code written for `tokio::fs::File` would be.
+  async fn round_trip<F>(f: &mut F) -> io::Result<String>
+  where F: tokio::io::AsyncRead + tokio::io::AsyncWrite + tokio::io::AsyncSeek + Unpin {
+    use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+    f.write_all(b"async hello").await?;
+    f.seek(SeekFrom::Start(6)).await?;
+    let mut out = String::new();
+    f.read_to_string(&mut out).await?;
+    Ok(out)
+  }
+  let mut f = File::new("async.txt");
+  f.open().unwrap();
+  let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+  let echoed = runtime.block_on(round_trip(&mut f));
+  println!("async read back {:?}", echoed);
+
+  let locks = lock::LockManager::new();
+  let a = locks.share(File::new("a.txt"), lock::LockPolicy::Mandatory);
+  let b = locks.share(File::new("b.txt"), lock::LockPolicy::Mandatory);
+  let barrier = std::sync::Arc::new(std::sync::Barrier::new(2));
+  let handles: Vec<_> = [(a.clone(), b.clone()), (b, a)].into_iter().map(|(first, second)| {
+    let barrier = barrier.clone();
+    std::thread::spawn(move || {
+      let held = first.lock_exclusive().unwrap();
+      barrier.wait();
+      let result = second.lock_exclusive_timeout(std::time::Duration::from_millis(500)).map(|g| g.name.clone());
+      println!("{} then second lock: {:?}", held.name, result);
+    })
+  }).collect();
+  for handle in handles {
+    handle.join().unwrap();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn write_then_read_back() {
+    let mut f = File::new("a.txt");
+    f.open().unwrap();
+    assert_eq!(f.write(b"hello").unwrap(), 5);
+    f.seek(SeekFrom::Start(1)).unwrap();
+    let mut buf = [0u8; 8];
+    assert_eq!(f.read(&mut buf).unwrap(), 4);
+    assert_eq!(&buf[..4], b"ello");
+    assert_eq!(f.read(&mut buf).unwrap(), 0);
+  }
+
+  #[test]
+  fn seek_past_end_zero_fills() {
+    let mut f = File::new("a.txt");
+    f.open().unwrap();
+    f.seek(SeekFrom::End(3)).unwrap();
+    f.write(b"x").unwrap();
+    assert_eq!(&*f.contents(), b"\0\0\0x");
+    assert_eq!(f.seek(SeekFrom::Current(-5)), Err(FileError::InvalidSeek));
+  }
+
+  #[test]
+  fn insert_remove_and_truncate() {
+    let mut f = File::new_with_data("a.txt", b"held");
+    f.open().unwrap();
+    f.insert_at(2, b"llo wor").unwrap();
+    assert_eq!(&*f.contents(), b"hello world");
+    f.remove_range(0..6).unwrap();
+    f.truncate(3).unwrap();
+    assert_eq!(&*f.contents(), b"wor");
+  }
+
+  #[test]
+  fn closed_file_refuses_io() {
+    let mut f = File::new("a.txt");
+    assert_eq!(f.write(b"x"), Err(FileError::NotOpen));
+    assert_eq!(f.read(&mut [0u8; 1]), Err(FileError::NotOpen));
+  }
+
+  #[test]
+  fn std_io_traits() {
+    let mut f = File::new("a.txt");
+    f.open().unwrap();
+    Write::write_all(&mut f, b"abc").unwrap();
+    Seek::seek(&mut f, SeekFrom::Start(0)).unwrap();
+    let mut s = String::new();
+    Read::read_to_string(&mut f, &mut s).unwrap();
+    assert_eq!(s, "abc");
+  }
+}
@@ -0,0 +1,472 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::fmt::Display;
+use std::sync::Arc;
+
+use crate::{File, FileError};
+use crate::fault::FaultPlan;
+use crate::hash::ContentStore;
+use crate::quota::Quota;
+use crate::state::FileState;
+use crate::watch::{DirWatch, WatchEvent};
+
+#[derive(Debug)]
+enum Node {
+  File(Box<File>),
+  Dir(BTreeMap<String, Node>),
+  /// A symbolic link holding the path it points to, which need not exist.
+  Symlink(String),
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum NodeKind {
+  File,
+  Dir,
+  Symlink,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Metadata {
+  pub kind: NodeKind,
+  /// Bytes for files, entries for directories, and the target's length for symlinks.
+  pub len: usize,
+  /// `None` for directories and symlinks.
+  pub state: Option<FileState>,
+  /// Names sharing a file's data; 1 for anything not hard linked.
+  pub links: usize,
+}
+
+/// One line of a `list_dir` listing; files print with `File`'s own `Display`.
+#[derive(Debug)]
+pub enum DirEntry<'a> {
+  File(&'a File),
+  Dir(&'a str),
+  Symlink { name: &'a str, target: &'a str },
+}
+
+/// Most symlinks one lookup follows before failing with `SymlinkLoop`, as on Linux.
+pub const MAX_SYMLINK_HOPS: usize = 40;
+
+/// An in-memory directory tree of `File`s addressed by `/`-separated paths.
+#[derive(Debug,Default)]
+pub struct MemFs {
+  root: BTreeMap<String, Node>,
+  pub(crate) dir_watches: Vec<DirWatch>,
+  pub(crate) quota: Arc<Quota>,
+  pub(crate) store: ContentStore,
+  pub(crate) faults: Option<FaultPlan>,
+}
+
+impl Display for DirEntry<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      DirEntry::File(file) => write!(f, "{}", file),
+      DirEntry::Dir(name) => write!(f, "{}/", name),
+      DirEntry::Symlink { name, target } => write!(f, "{} -> {}", name, target),
+    }
+  }
+}
+
+impl Node {
+  fn metadata(&self) -> Metadata {
+    match self {
+      Node::File(file) => Metadata {
+        kind: NodeKind::File,
+        len: file.len(),
+        state: Some(file.state),
+        links: file.link_count(),
+      },
+      Node::Dir(children) => Metadata {
+        kind: NodeKind::Dir,
+        len: children.len(),
+        state: None,
+        links: 1,
+      },
+      Node::Symlink(target) => Metadata {
+        kind: NodeKind::Symlink,
+        len: target.len(),
+        state: None,
+        links: 1,
+      },
+    }
+  }
+}
+
+/// Splits a path into its components, resolving `.` and `..` lexically.
+fn components(path: &str) -> Result<Vec<String>, FileError> {
+  let mut parts: Vec<String> = Vec::new();
+  for part in path.split('/') {
+    match part {
+      "" | "." => {}
+      ".." => {
+        parts.pop().ok_or(FileError::InvalidPath)?;
+      }
+      _ => parts.push(part.to_string()),
+    }
+  }
+  Ok(parts)
+}
+
+/// Calls `f` with the path relative to `dir` (starting with `/`) of every file below it.
+fn visit_files_mut(dir: &mut BTreeMap<String, Node>, prefix: &str, f: &mut dyn FnMut(String, &mut File)) {
+  for (name, node) in dir.iter_mut() {
+    let path = format!("{}/{}", prefix, name);
+    match node {
+      Node::File(file) => f(path, file),
+      Node::Dir(children) => visit_files_mut(children, &path, f),
+      Node::Symlink(_) => {}
+    }
+  }
+}
+
+impl MemFs {
+  pub fn new() -> MemFs {
+    MemFs::default()
+  }
+
+  fn dir(&self, parts: &[String]) -> Result<&BTreeMap<String, Node>, FileError> {
+    let mut dir = &self.root;
+    for part in parts {
+      dir = match dir.get(part) {
+        Some(Node::Dir(children)) => children,
+        Some(Node::File(_) | Node::Symlink(_)) => return Err(FileError::NotADirectory),
+        None => return Err(FileError::NotFound),
+      };
+    }
+    Ok(dir)
+  }
+
+  fn dir_mut(&mut self, parts: &[String]) -> Result<&mut BTreeMap<String, Node>, FileError> {
+    let mut dir = &mut self.root;
+    for part in parts {
+      dir = match dir.get_mut(part) {
+        Some(Node::Dir(children)) => children,
+        Some(Node::File(_) | Node::Symlink(_)) => return Err(FileError::NotADirectory),
+        None => return Err(FileError::NotFound),
+      };
+    }
+    Ok(dir)
+  }
+
+  /// The components of `path` with every symlink on the way expanded, and the
+  /// last component too if `follow_last` is set. `..` applies to where a
+  /// symlink led, not to the link itself.
+  fn resolve(&self, path: &str, follow_last: bool) -> Result<Vec<String>, FileError> {
+    let mut pending: VecDeque<String> = path.split('/').filter(|p| !p.is_empty()).map(String::from).collect();
+    let mut resolved: Vec<String> = Vec::new();
+    let mut hops = 0;
+    while let Some(part) = pending.pop_front() {
+      match part.as_str() {
+        "." => continue,
+        ".." => {
+          resolved.pop().ok_or(FileError::InvalidPath)?;
+          continue;
+        }
+        _ => {}
+      }
+      let last = pending.is_empty();
+      match self.dir(&resolved)?.get(&part) {
+        Some(Node::Symlink(target)) if follow_last || !last => {
+          hops += 1;
+          if hops > MAX_SYMLINK_HOPS {
+            return Err(FileError::SymlinkLoop);
+          }
+          if target.starts_with('/') {
+            resolved.clear();
+          }
+          for step in target.split('/').rev().filter(|p| !p.is_empty()) {
+            pending.push_front(step.to_string());
+          }
+        }
+        Some(Node::File(_)) if !last => return Err(FileError::NotADirectory),
+        _ => resolved.push(part),
+      }
+    }
+    Ok(resolved)
+  }
+
+  /// The real directory holding `path` and the name in it; the root has no name.
+  fn locate(&self, path: &str, follow_last: bool) -> Result<(Vec<String>, String), FileError> {
+    let mut parts = self.resolve(path, follow_last)?;
+    let name = parts.pop().ok_or(FileError::InvalidPath)?;
+    Ok((parts, name))
+  }
+
+  /// The directory and name a new entry at `path` would take, failing if the
+  /// directory is missing or the name is taken.
+  pub(crate) fn free_name(&self, path: &str) -> Result<(Vec<String>, String), FileError> {
+    let (parent, name) = self.locate(path, false)?;
+    if self.dir(&parent)?.contains_key(&name) {
+      return Err(FileError::AlreadyExists);
+    }
+    Ok((parent, name))
+  }
+
+  fn node(&self, path: &str, follow_last: bool) -> Result<&Node, FileError> {
+    let (parent, name) = self.locate(path, follow_last)?;
+    self.dir(&parent)?.get(&name).ok_or(FileError::NotFound)
+  }
+
+  /// The absolute path `path` leads to once symlinks are followed.
+  pub fn real_path(&self, path: &str) -> Result<String, FileError> {
+    Ok(format!("/{}", self.resolve(path, true)?.join("/")))
+  }
+
+  /// Whether `path` leads anywhere; a dangling symlink does not.
+  pub fn exists(&self, path: &str) -> bool {
+    self.metadata(path).is_ok()
+  }
+
+  pub fn mkdir(&mut self, path: &str) -> Result<(), FileError> {
+    let (parent, name) = self.locate(path, false)?;
+    let dir = self.dir_mut(&parent)?;
+    if dir.contains_key(&name) {
+      return Err(FileError::AlreadyExists);
+    }
+    dir.insert(name, Node::Dir(BTreeMap::new()));
+    Ok(())
+  }
+
+  /// Creates `path` and any missing parents; existing directories, and
+  /// symlinks to them, are left alone.
+  pub fn mkdir_all(&mut self, path: &str) -> Result<(), FileError> {
+    let mut prefix = String::new();
+    for part in components(path)? {
+      prefix = format!("{}/{}", prefix, part);
+      match self.metadata(&prefix) {
+        Ok(meta) if meta.kind == NodeKind::Dir => {}
+        Ok(_) => return Err(FileError::NotADirectory),
+        Err(FileError::NotFound) => self.mkdir(&prefix)?,
+        Err(e) => return Err(e),
+      }
+    }
+    Ok(())
+  }
+
+  /// Creates an empty, closed file at `path`; the parent directory must exist.
+  pub fn create(&mut self, path: &str) -> Result<&mut File, FileError> {
+    let (parent, name) = self.free_name(path)?;
+    let mut file = File::new(&name);
+    file.attach_quota(&self.quota)?;
+    self.inject_faults_into(&parent, &name, &mut file);
+    self.dir_mut(&parent)?.insert(name.clone(), Node::File(Box::new(file)));
+    self.rewatch();
+    self.file_at(&parent, &name)
+  }
+
+  /// Places an existing `File` at `path`, renaming it to match. Fails with
+  /// `OutOfSpace` if it doesn't fit the quota.
+  pub fn insert(&mut self, path: &str, mut file: File) -> Result<&mut File, FileError> {
+    let (parent, name) = self.free_name(path)?;
+    file.name = name.clone();
+    file.attach_quota(&self.quota)?;
+    self.inject_faults_into(&parent, &name, &mut file);
+    self.dir_mut(&parent)?.insert(name.clone(), Node::File(Box::new(file)));
+    self.rewatch();
+    self.file_at(&parent, &name)
+  }
+
+  pub fn file(&self, path: &str) -> Result<&File, FileError> {
+    match self.node(path, true)? {
+      Node::File(file) => Ok(file),
+      Node::Dir(_) => Err(FileError::IsADirectory),
+      Node::Symlink(_) => Err(FileError::NotFound),
+    }
+  }
+
+  pub fn file_mut(&mut self, path: &str) -> Result<&mut File, FileError> {
+    let (parent, name) = self.locate(path, true)?;
+    self.file_at(&parent, &name)
+  }
+
+  fn file_at(&mut self, parent: &[String], name: &str) -> Result<&mut File, FileError> {
+    match self.dir_mut(parent)?.get_mut(name) {
+      Some(Node::File(file)) => Ok(file),
+      Some(Node::Dir(_)) => Err(FileError::IsADirectory),
+      Some(Node::Symlink(_)) | None => Err(FileError::NotFound),
+    }
+  }
+
+  /// Creates a symlink at `path` pointing to `target`, which is not checked:
+  /// it may dangle, be relative to the link's directory, or lead back to itself.
+  pub fn symlink(&mut self, target: &str, path: &str) -> Result<(), FileError> {
+    if target.is_empty() {
+      return Err(FileError::InvalidPath);
+    }
+    let (parent, name) = self.locate(path, false)?;
+    let dir = self.dir_mut(&parent)?;
+    if dir.contains_key(&name) {
+      return Err(FileError::AlreadyExists);
+    }
+    dir.insert(name, Node::Symlink(target.to_string()));
+    Ok(())
+  }
+
+  /// Where the symlink at `path` points; `InvalidPath` if it is not a symlink.
+  pub fn read_link(&self, path: &str) -> Result<&str, FileError> {
+    match self.node(path, false)? {
+      Node::Symlink(target) => Ok(target),
+      _ => Err(FileError::InvalidPath),
+    }
+  }
+
+  /// Moves a file, directory or symlink; the destination must not exist yet.
+  /// A symlink is moved itself, not what it points to.
+  pub fn rename(&mut self, from: &str, to: &str) -> Result<(), FileError> {
+    let (from_parent, from_name) = self.locate(from, false)?;
+    let (to_parent, to_name) = self.locate(to, false)?;
+    let mut from_parts = from_parent.clone();
+    from_parts.push(from_name.clone());
+    if to_parent.starts_with(&from_parts) {
+      return Err(FileError::InvalidPath);
+    }
+
+    if !self.dir(&from_parent)?.contains_key(&from_name) {
+      return Err(FileError::NotFound);
+    }
+    if self.dir(&to_parent)?.contains_key(&to_name) {
+      return Err(FileError::AlreadyExists);
+    }
+
+    let mut node = self.dir_mut(&from_parent)?.remove(&from_name).ok_or(FileError::NotFound)?;
+    let mut to_parts = to_parent.clone();
+    to_parts.push(to_name.clone());
+    let (from, to) = (format!("/{}", from_parts.join("/")), format!("/{}", to_parts.join("/")));
+    match &mut node {
+      Node::File(file) => {
+        file.notify(WatchEvent::Renamed { from, to });
+        file.name = to_name.clone();
+      }
+      Node::Dir(children) => visit_files_mut(children, "", &mut |rel, file| {
+        file.notify(WatchEvent::Renamed { from: format!("{}{}", from, rel), to: format!("{}{}", to, rel) });
+      }),
+      Node::Symlink(_) => {}
+    }
+    self.dir_mut(&to_parent)?.insert(to_name, node);
+    self.rewatch();
+    Ok(())
+  }
+
+  /// Removes a file, a symlink (not what it points to) or an empty directory.
+  /// Other hard links to a removed file keep its data.
+  pub fn remove(&mut self, path: &str) -> Result<(), FileError> {
+    let (parent, name) = self.locate(path, false)?;
+    let dir = self.dir_mut(&parent)?;
+    match dir.get(&name) {
+      None => return Err(FileError::NotFound),
+      Some(Node::Dir(children)) if !children.is_empty() => {
+        return Err(FileError::DirectoryNotEmpty);
+      }
+      Some(_) => {}
+    }
+    if let Some(Node::File(mut file)) = dir.remove(&name) {
+      file.detach_quota();
+      file.notify(WatchEvent::Deleted);
+    }
+    Ok(())
+  }
+
+  /// Lists a directory in name order.
+  pub fn list_dir(&self, path: &str) -> Result<Vec<DirEntry<'_>>, FileError> {
+    let dir = self.dir(&self.resolve(path, true)?)?;
+    Ok(dir.iter().map(|(name, node)| match node {
+      Node::File(file) => DirEntry::File(file),
+      Node::Dir(_) => DirEntry::Dir(name),
+      Node::Symlink(target) => DirEntry::Symlink { name, target },
+    }).collect())
+  }
+
+  /// Calls `f` with the absolute path of every file.
+  pub(crate) fn for_each_file_mut(&mut self, mut f: impl FnMut(String, &mut File)) {
+    visit_files_mut(&mut self.root, "", &mut f);
+  }
+
+  /// Every directory, file and symlink with its absolute path, parents before
+  /// children. Symlinks are listed, not followed.
+  pub fn walk(&self) -> Vec<(String, DirEntry<'_>)> {
+    fn visit<'a>(dir: &'a BTreeMap<String, Node>, prefix: &str, out: &mut Vec<(String, DirEntry<'a>)>) {
+      for (name, node) in dir {
+        let path = format!("{}/{}", prefix, name);
+        match node {
+          Node::File(file) => out.push((path, DirEntry::File(file))),
+          Node::Dir(children) => {
+            out.push((path.clone(), DirEntry::Dir(name)));
+            visit(children, &path, out);
+          }
+          Node::Symlink(target) => out.push((path, DirEntry::Symlink { name, target })),
+        }
+      }
+    }
+    let mut out = Vec::new();
+    visit(&self.root, "", &mut out);
+    out
+  }
+
+  /// Metadata of whatever `path` leads to, following symlinks.
+  pub fn metadata(&self, path: &str) -> Result<Metadata, FileError> {
+    if self.resolve(path, true)?.is_empty() {
+      return Ok(Metadata { kind: NodeKind::Dir, len: self.root.len(), state: None, links: 1 });
+    }
+    Ok(self.node(path, true)?.metadata())
+  }
+
+  /// Like `metadata`, but describes a symlink at `path` rather than its target.
+  pub fn symlink_metadata(&self, path: &str) -> Result<Metadata, FileError> {
+    if self.resolve(path, false)?.is_empty() {
+      return self.metadata("/");
+    }
+    Ok(self.node(path, false)?.metadata())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn create_list_and_remove() {
+    let mut fs = MemFs::new();
+    fs.mkdir_all("/a/b").unwrap();
+    fs.create("/a/b/f.txt").unwrap();
+    fs.create("/a/g.txt").unwrap();
+    let names: Vec<String> = fs.list_dir("/a").unwrap().iter().map(|e| e.to_string()).collect();
+    assert_eq!(names.len(), 2);
+    assert_eq!(fs.remove("/a/b"), Err(FileError::DirectoryNotEmpty));
+    fs.remove("/a/b/f.txt").unwrap();
+    fs.remove("/a/b").unwrap();
+    assert!(!fs.exists("/a/b"));
+  }
+
+  #[test]
+  fn path_errors() {
+    let mut fs = MemFs::new();
+    fs.create("/f").unwrap();
+    assert_eq!(fs.create("/f").err(), Some(FileError::AlreadyExists));
+    assert_eq!(fs.create("/missing/f").err(), Some(FileError::NotFound));
+    assert_eq!(fs.create("/f/g").err(), Some(FileError::NotADirectory));
+    assert_eq!(fs.mkdir_all("/f/g"), Err(FileError::NotADirectory));
+    fs.mkdir("/d").unwrap();
+    assert_eq!(fs.file("/d").err(), Some(FileError::IsADirectory));
+  }
+
+  #[test]
+  fn rename_moves_files_and_directories() {
+    let mut fs = MemFs::new();
+    fs.mkdir("/d").unwrap();
+    fs.create("/d/f").unwrap();
+    fs.rename("/d", "/e").unwrap();
+    assert!(fs.file("/e/f").is_ok());
+    fs.rename("/e/f", "/g").unwrap();
+    assert_eq!(fs.file("/g").unwrap().name, "g");
+    assert_eq!(fs.rename("/e", "/e/inside"), Err(FileError::InvalidPath));
+  }
+
+  #[test]
+  fn walk_lists_parents_first() {
+    let mut fs = MemFs::new();
+    fs.mkdir_all("/a/b").unwrap();
+    fs.create("/a/b/f").unwrap();
+    let paths: Vec<String> = fs.walk().into_iter().map(|(path, _)| path).collect();
+    assert_eq!(paths, ["/a", "/a/b", "/a/b/f"]);
+  }
+}
@@ -0,0 +1,146 @@
+use std::fmt;
+use std::fmt::Display;
+
+pub const ROOT: &str = "root";
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Access {
+  Read,
+  Write,
+  Execute,
+}
+
+/// The identity a `File` handle acts as when checking permissions.
+#[derive(Debug,Clone,PartialEq)]
+pub struct User {
+  pub name: String,
+  pub groups: Vec<String>,
+}
+
+/// Unix-style ownership and `rwxrwxrwx` mode bits.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Permissions {
+  pub owner: String,
+  pub group: String,
+  pub mode: u16,
+}
+
+impl User {
+  pub fn new(name: &str, groups: &[&str]) -> User {
+    User {
+      name: String::from(name),
+      groups: groups.iter().map(|g| g.to_string()).collect(),
+    }
+  }
+
+  /// The superuser, which passes every permission check.
+  pub fn root() -> User {
+    User::new(ROOT, &[ROOT])
+  }
+
+  pub fn is_root(&self) -> bool {
+    self.name == ROOT
+  }
+
+  pub fn in_group(&self, group: &str) -> bool {
+    self.groups.iter().any(|g| g == group)
+  }
+}
+
+impl Default for User {
+  fn default() -> User {
+    User::root()
+  }
+}
+
+impl Permissions {
+  pub fn new(owner: &str, group: &str, mode: u16) -> Permissions {
+    Permissions {
+      owner: String::from(owner),
+      group: String::from(group),
+      mode: mode & 0o777,
+    }
+  }
+
+  /// The mode alone, `ls -l` style: `-rw-r--r--`.
+  pub fn mode_string(&self) -> String {
+    let mut bits = String::from("-");
+    for shift in [6, 3, 0] {
+      let class = self.mode >> shift;
+      bits.push(if class & 0o4 != 0 { 'r' } else { '-' });
+      bits.push(if class & 0o2 != 0 { 'w' } else { '-' });
+      bits.push(if class & 0o1 != 0 { 'x' } else { '-' });
+    }
+    bits
+  }
+
+  /// Checks the owner, group or other bits, whichever class `user` falls in first.
+  pub fn allows(&self, user: &User, access: Access) -> bool {
+    if user.is_root() {
+      return true;
+    }
+    let shift = if user.name == self.owner {
+      6
+    } else if user.in_group(&self.group) {
+      3
+    } else {
+      0
+    };
+    let bit = match access {
+      Access::Read => 0o4,
+      Access::Write => 0o2,
+      Access::Execute => 0o1,
+    };
+    (self.mode >> shift) & bit != 0
+  }
+}
+
+impl Default for Permissions {
+  fn default() -> Permissions {
+    Permissions::new(ROOT, ROOT, 0o644)
+  }
+}
+
+impl Display for Permissions {
+  /// `ls -l` style: `-rw-r--r-- owner group`.
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{} {} {}", self.mode_string(), self.owner, self.group)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{File, FileError};
+
+  #[test]
+  fn first_matching_class_decides() {
+    let perms = Permissions::new("alice", "staff", 0o604);
+    let alice = User::new("alice", &["staff"]);
+    let bob = User::new("bob", &["staff"]);
+    let eve = User::new("eve", &[]);
+    assert!(perms.allows(&alice, Access::Write));
+    // Bob is in the group, whose bits grant nothing, even though others may read.
+    assert!(!perms.allows(&bob, Access::Read));
+    assert!(perms.allows(&eve, Access::Read));
+    assert!(perms.allows(&User::root(), Access::Execute));
+  }
+
+  #[test]
+  fn mode_string() {
+    assert_eq!(Permissions::new("a", "b", 0o754).mode_string(), "-rwxr-xr--");
+  }
+
+  #[test]
+  fn file_checks_its_user() {
+    let mut f = File::new_with_data("a.txt", b"secret");
+    f.chown("alice", "staff").unwrap();
+    f.chmod(0o600).unwrap();
+    f.set_user(User::new("bob", &["staff"]));
+    assert_eq!(f.open_read(), Err(FileError::PermissionDenied));
+    assert_eq!(f.chmod(0o644), Err(FileError::PermissionDenied));
+    f.set_user(User::new("alice", &[]));
+    f.open_read().unwrap();
+    assert_eq!(f.chown("bob", "staff"), Err(FileError::PermissionDenied));
+  }
+}
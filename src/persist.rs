@@ -0,0 +1,169 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+use crate::crypt::{load_sealed_flag, store_sealed_flag};
+use crate::time::Timestamps;
+use crate::{File, FileError};
+
+/// Tells apart temp files staged by threads of the same process.
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A sibling of `path` to stage writes in before renaming over it, unique to
+/// this call.
+fn temp_path(path: &Path) -> PathBuf {
+  let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+  let n = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+  path.with_file_name(format!(".{}.{}.{}.tmp", name, process::id(), n))
+}
+
+/// Makes a rename in the directory holding `path` durable.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> std::io::Result<()> {
+  let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+  fs::File::open(dir)?.sync_all()
+}
+
+/// Directories can't be opened for syncing here; the rename is left to the OS.
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> std::io::Result<()> {
+  Ok(())
+}
+
+/// Replaces `path` with `data` so readers see either the old or the new contents, never a mix.
+pub(crate) fn write_atomic(path: &Path, data: &[u8]) -> Result<(), FileError> {
+  let tmp = temp_path(path);
+  let result = (|| {
+    let mut out = fs::File::create(&tmp)?;
+    out.write_all(data)?;
+    out.sync_all()?;
+    fs::rename(&tmp, path)?;
+    sync_parent(path)
+  })();
+  if result.is_err() {
+    let _ = fs::remove_file(&tmp);
+  }
+  result.map_err(FileError::from)
+}
+
+impl File {
+  /// Reads `path` from disk into a closed, clean `File` backed by that path.
+  /// Data flushed while encrypted loads as an encrypted file, going by the
+  /// marker `flush` leaves next to it; the data itself is never sniffed.
+  pub fn load<P: AsRef<Path>>(path: P) -> Result<File, FileError> {
+    let path = path.as_ref();
+    let name = path.file_name().ok_or(FileError::InvalidPath)?.to_string_lossy();
+    let mut file = File::new(&name);
+    let data = fs::read(path)?;
+    file.encrypted = load_sealed_flag(path)?;
+    file.data = Box::new(data);
+    if let Ok(meta) = fs::metadata(path) {
+      let now = SystemTime::now();
+      let modified = meta.modified().unwrap_or(now);
+      file.times = Timestamps {
+        created: meta.created().unwrap_or(modified),
+        modified,
+        accessed: meta.accessed().unwrap_or(now),
+      };
+    }
+    file.backing = Some(path.to_path_buf());
+    Ok(file)
+  }
+
+  pub fn backing_path(&self) -> Option<&Path> {
+    self.backing.as_deref()
+  }
+
+  pub fn is_dirty(&self) -> bool {
+    self.dirty
+  }
+
+  /// Writes the data to the backing file if it changed since the last load or flush.
+  /// Files with no backing path have nothing to flush.
+  pub fn flush(&mut self) -> Result<(), FileError> {
+    let Some(path) = &self.backing else {
+      return Ok(());
+    };
+    if self.dirty {
+      write_atomic(path, &self.data.contents())?;
+      store_sealed_flag(path, self.encrypted)?;
+      self.reset_journal()?;
+      self.dirty = false;
+    }
+    Ok(())
+  }
+
+  /// Makes `path` the backing file and writes to it, skipping the write if `path`
+  /// already holds the current data.
+  pub fn sync_to<P: AsRef<Path>>(&mut self, path: P) -> Result<(), FileError> {
+    let path = path.as_ref();
+    if self.backing.as_deref() != Some(path) {
+      self.backing = Some(path.to_path_buf());
+      self.dirty = true;
+      if self.journal.is_some() {
+        self.flush()?;
+        return self.move_journal();
+      }
+    }
+    self.flush()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// An empty directory of its own for each test.
+  fn scratch(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("memfs-persist-{}-{}", process::id(), test));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  #[test]
+  fn flush_then_load_round_trips() {
+    let dir = scratch("round-trip");
+    let path = dir.join("a.txt");
+    let mut f = File::new("a.txt");
+    f.open().unwrap();
+    f.write(b"on disk").unwrap();
+    f.sync_to(&path).unwrap();
+    assert!(!f.is_dirty());
+    let loaded = File::load(&path).unwrap();
+    assert_eq!(&*loaded.contents(), b"on disk");
+    assert_eq!(loaded.backing_path(), Some(path.as_path()));
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn flush_writes_only_when_dirty() {
+    let dir = scratch("dirty");
+    let path = dir.join("a.txt");
+    fs::write(&path, b"old").unwrap();
+    let mut f = File::load(&path).unwrap();
+    fs::write(&path, b"changed behind our back").unwrap();
+    f.flush().unwrap();
+    assert_eq!(fs::read(&path).unwrap(), b"changed behind our back");
+    f.open().unwrap();
+    f.write(b"new").unwrap();
+    f.flush().unwrap();
+    assert_eq!(fs::read(&path).unwrap(), b"new");
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn write_atomic_leaves_no_temp_files() {
+    let dir = scratch("atomic");
+    let path = dir.join("a.txt");
+    write_atomic(&path, b"one").unwrap();
+    write_atomic(&path, b"two").unwrap();
+    let names: Vec<_> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+    assert_eq!(names, ["a.txt"]);
+    assert_ne!(temp_path(&path), temp_path(&path));
+    fs::remove_dir_all(dir).unwrap();
+  }
+}
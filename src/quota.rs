@@ -0,0 +1,397 @@
+//! Size limits: a cap on each `File`, and a `MemFs`-wide quota on bytes and
+//! file count. Growth past either fails with `OutOfSpace` and leaves the data
+//! untouched. Limits count logical length, not `stored_len`, and hard linked
+//! data once however many links hold it.
+
+use std::fmt;
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::link::Linked;
+use crate::memfs::{DirEntry, MemFs};
+use crate::storage::Storage;
+use crate::{File, FileError};
+
+/// Limits and running totals shared by every file in a `MemFs`.
+#[derive(Debug)]
+pub struct Quota {
+  max_bytes: AtomicUsize,
+  max_files: AtomicUsize,
+  bytes: AtomicUsize,
+  files: AtomicUsize,
+}
+
+/// A file's own cap, plus its share of the quota of the `MemFs` it lives in.
+#[derive(Debug,Default)]
+pub struct Limits {
+  max_size: Option<usize>,
+  quota: Option<Arc<Quota>>,
+  /// Bytes this file has counted against `quota`, unless its data is hard
+  /// linked and counted in a `SharedCharge`.
+  charged: usize,
+}
+
+/// The quota charge for hard linked data, made once for all its links.
+#[derive(Debug,Default)]
+pub struct SharedCharge {
+  /// Links to the data that count against a quota.
+  holders: usize,
+  bytes: usize,
+}
+
+/// Space used by a `MemFs`, as reported by `MemFs::usage`.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Usage {
+  pub bytes: usize,
+  /// Bytes held in memory after compression, with deduplicated and hard linked
+  /// data counted once.
+  pub stored_bytes: usize,
+  pub files: usize,
+  pub dirs: usize,
+  pub max_bytes: Option<usize>,
+  pub max_files: Option<usize>,
+}
+
+/// Runs `f` on the bytes charged for `data`: the shared charge if it is hard
+/// linked, else `limits.charged`. `None` if there is no quota.
+fn with_charge<R>(limits: &mut Limits, data: &dyn Storage, f: impl FnOnce(&Quota, &mut usize) -> R) -> Option<R> {
+  let quota = limits.quota.as_ref()?;
+  match data.as_linked() {
+    Some(linked) => Some(f(quota, &mut linked.charge().bytes)),
+    None => Some(f(quota, &mut limits.charged)),
+  }
+}
+
+fn limit(value: &AtomicUsize) -> Option<usize> {
+  Some(value.load(Ordering::Relaxed)).filter(|&max| max != usize::MAX)
+}
+
+/// Adds `delta` to `total` unless that would pass `max`.
+fn try_add(total: &AtomicUsize, max: &AtomicUsize, delta: usize) -> Result<(), FileError> {
+  let max = max.load(Ordering::Relaxed);
+  total
+    .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| used.checked_add(delta).filter(|&n| n <= max))
+    .map(|_| ())
+    .map_err(|_| FileError::OutOfSpace)
+}
+
+impl Default for Quota {
+  fn default() -> Quota {
+    Quota {
+      max_bytes: AtomicUsize::new(usize::MAX),
+      max_files: AtomicUsize::new(usize::MAX),
+      bytes: AtomicUsize::new(0),
+      files: AtomicUsize::new(0),
+    }
+  }
+}
+
+impl Quota {
+  /// Moves a file's charge from `old` to `new` bytes, failing if that grows past the limit.
+  fn charge(&self, old: usize, new: usize) -> Result<(), FileError> {
+    if new > old {
+      try_add(&self.bytes, &self.max_bytes, new - old)
+    } else {
+      self.bytes.fetch_sub(old - new, Ordering::AcqRel);
+      Ok(())
+    }
+  }
+
+  /// Fails with `OutOfSpace` if another file wouldn't fit.
+  pub(crate) fn check_file_room(&self) -> Result<(), FileError> {
+    if self.files.load(Ordering::Acquire) >= self.max_files.load(Ordering::Relaxed) {
+      return Err(FileError::OutOfSpace);
+    }
+    Ok(())
+  }
+
+  /// Like `charge`, but records growth that already happened even past the limit.
+  fn force(&self, old: usize, new: usize) {
+    if new > old {
+      self.bytes.fetch_add(new - old, Ordering::AcqRel);
+    } else {
+      self.bytes.fetch_sub(old - new, Ordering::AcqRel);
+    }
+  }
+}
+
+impl Display for Usage {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{} bytes ({} stored) in {} files, {} dirs", self.bytes, self.stored_bytes, self.files, self.dirs)?;
+    match (self.max_bytes, self.max_files) {
+      (None, None) => Ok(()),
+      (bytes, files) => {
+        let show = |n: Option<usize>| n.map_or("unlimited".to_string(), |n| n.to_string());
+        write!(f, "; quota {} bytes, {} files", show(bytes), show(files))
+      }
+    }
+  }
+}
+
+impl Usage {
+  pub fn bytes_free(&self) -> Option<usize> {
+    self.max_bytes.map(|max| max.saturating_sub(self.bytes))
+  }
+
+  pub fn files_free(&self) -> Option<usize> {
+    self.max_files.map(|max| max.saturating_sub(self.files))
+  }
+}
+
+impl File {
+  /// Caps the data at `max` bytes; `None` lifts the cap. Data already past a
+  /// new cap is kept, but can't grow.
+  pub fn set_max_size(&mut self, max: Option<usize>) {
+    self.limits.max_size = max;
+  }
+
+  pub fn max_size(&self) -> Option<usize> {
+    self.limits.max_size
+  }
+
+  /// Checks that the data may become `new_len` bytes long and charges any
+  /// growth to the quota. Called before each operation that can grow the data.
+  pub(crate) fn reserve(&mut self, new_len: usize) -> Result<(), FileError> {
+    let len = self.data.len();
+    if new_len > len && self.limits.max_size.is_some_and(|max| new_len > max) {
+      return Err(FileError::OutOfSpace);
+    }
+    let target = new_len.max(len);
+    with_charge(&mut self.limits, &*self.data, |quota, charged| {
+      quota.charge(*charged, target)?;
+      *charged = target;
+      Ok(())
+    }).unwrap_or(Ok(()))
+  }
+
+  /// Reserves for `new_len` like `reserve`, then runs `op`, the logging and the
+  /// change itself. If `op` fails the charge goes back to the actual length, so
+  /// the quota never counts bytes that weren't written.
+  pub(crate) fn reserved<T>(&mut self, new_len: usize, op: impl FnOnce(&mut File) -> Result<T, FileError>) -> Result<T, FileError> {
+    self.reserve(new_len)?;
+    let result = op(self);
+    if result.is_err() {
+      self.settle();
+    }
+    result
+  }
+
+  /// Brings the quota charge in line with the actual length after a change.
+  pub(crate) fn settle(&mut self) {
+    let len = self.data.len();
+    with_charge(&mut self.limits, &*self.data, |quota, charged| {
+      quota.force(*charged, len);
+      *charged = len;
+    });
+  }
+
+  /// Counts this file against `quota`; fails if it doesn't fit. Hard linked
+  /// data another counted link holds is already paid for.
+  pub(crate) fn attach_quota(&mut self, quota: &Arc<Quota>) -> Result<(), FileError> {
+    try_add(&quota.files, &quota.max_files, 1)?;
+    let len = self.data.len();
+    let mut shared = self.data.as_linked().map(Linked::charge);
+    if shared.as_ref().is_none_or(|shared| shared.holders == 0)
+      && let Err(e) = try_add(&quota.bytes, &quota.max_bytes, len)
+    {
+      quota.files.fetch_sub(1, Ordering::AcqRel);
+      return Err(e);
+    }
+    match &mut shared {
+      Some(shared) => {
+        if shared.holders == 0 {
+          shared.bytes = len;
+        }
+        shared.holders += 1;
+      }
+      None => self.limits.charged = len,
+    }
+    drop(shared);
+    self.limits.quota = Some(quota.clone());
+    Ok(())
+  }
+
+  pub(crate) fn detach_quota(&mut self) {
+    self.release_charge();
+    if let Some(quota) = self.limits.quota.take() {
+      quota.files.fetch_sub(1, Ordering::AcqRel);
+    }
+  }
+
+  /// Gives back this file's part of the charge for its data, before the data
+  /// goes. Shared data stays charged while another counted link holds it.
+  pub(crate) fn release_charge(&mut self) {
+    let Some(quota) = &self.limits.quota else { return };
+    let released = match self.data.as_linked() {
+      Some(linked) => {
+        let mut shared = linked.charge();
+        shared.holders = shared.holders.saturating_sub(1);
+        if shared.holders == 0 { std::mem::take(&mut shared.bytes) } else { 0 }
+      }
+      None => std::mem::take(&mut self.limits.charged),
+    };
+    quota.force(released, 0);
+  }
+
+  /// Moves this file's charge onto its data once it has become hard linked.
+  pub(crate) fn share_charge(&mut self) {
+    if self.limits.quota.is_some()
+      && let Some(linked) = self.data.as_linked()
+    {
+      let mut shared = linked.charge();
+      shared.holders += 1;
+      shared.bytes += std::mem::take(&mut self.limits.charged);
+    }
+  }
+}
+
+impl MemFs {
+  /// Limits the total bytes and number of files; `None` means unlimited. Usage
+  /// already past a new limit is kept, but can't grow.
+  pub fn set_quota(&mut self, max_bytes: Option<usize>, max_files: Option<usize>) {
+    self.quota.max_bytes.store(max_bytes.unwrap_or(usize::MAX), Ordering::Relaxed);
+    self.quota.max_files.store(max_files.unwrap_or(usize::MAX), Ordering::Relaxed);
+  }
+
+  pub fn usage(&self) -> Usage {
+    let mut usage = Usage {
+      bytes: self.quota.bytes.load(Ordering::Acquire),
+      stored_bytes: 0,
+      files: self.quota.files.load(Ordering::Acquire),
+      dirs: 0,
+      max_bytes: limit(&self.quota.max_bytes),
+      max_files: limit(&self.quota.max_files),
+    };
+    let mut shared = HashSet::new();
+    for (_, entry) in self.walk() {
+      match entry {
+        DirEntry::File(file) => {
+          if file.data.shared_id().is_none_or(|id| shared.insert(id)) {
+            usage.stored_bytes += file.stored_len();
+          }
+        }
+        DirEntry::Dir(_) => usage.dirs += 1,
+        DirEntry::Symlink { .. } => {}
+      }
+    }
+    usage
+  }
+
+  /// Total length of the file at `path`, or of every file below the directory at `path`.
+  pub fn disk_usage(&self, path: &str) -> Result<usize, FileError> {
+    if let Ok(file) = self.file(path) {
+      return Ok(file.len());
+    }
+    let list = self.list_dir(path)?;
+    let mut total = 0;
+    for entry in list {
+      total += match entry {
+        DirEntry::File(file) => file.len(),
+        DirEntry::Dir(name) => self.disk_usage(&format!("{}/{}", path.trim_end_matches('/'), name))?,
+        // Not followed, as `du` doesn't.
+        DirEntry::Symlink { .. } => 0,
+      };
+    }
+    Ok(total)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn append(fs: &mut MemFs, path: &str, data: &[u8]) -> Result<usize, FileError> {
+    let file = fs.file_mut(path)?;
+    file.open_append()?;
+    let written = file.write(data);
+    file.close()?;
+    written
+  }
+
+  #[test]
+  fn max_size_refuses_growth() {
+    let mut f = File::new("f");
+    f.set_max_size(Some(4));
+    f.open().unwrap();
+    assert_eq!(f.write(b"abcd"), Ok(4));
+    assert_eq!(f.write(b"e"), Err(FileError::OutOfSpace));
+    assert_eq!(&*f.contents(), b"abcd");
+    // Overwriting in place doesn't grow the data.
+    f.seek(std::io::SeekFrom::Start(0)).unwrap();
+    assert_eq!(f.write(b"ABCD"), Ok(4));
+  }
+
+  #[test]
+  fn quota_counts_bytes_and_files() {
+    let mut fs = MemFs::new();
+    fs.set_quota(Some(10), Some(2));
+    fs.create("/a").unwrap();
+    assert_eq!(append(&mut fs, "/a", b"0123456789"), Ok(10));
+    assert_eq!(append(&mut fs, "/a", b"!"), Err(FileError::OutOfSpace));
+    let big = File::new_with_data("b", b"x");
+    assert_eq!(fs.insert("/b", big).err(), Some(FileError::OutOfSpace));
+    let a = fs.file_mut("/a").unwrap();
+    a.open().unwrap();
+    a.truncate(4).unwrap();
+    a.close().unwrap();
+    fs.create("/b").unwrap();
+    assert_eq!(fs.create("/c").err(), Some(FileError::OutOfSpace));
+    let usage = fs.usage();
+    assert_eq!((usage.bytes, usage.files), (4, 2));
+    assert_eq!((usage.bytes_free(), usage.files_free()), (Some(6), Some(0)));
+    fs.remove("/a").unwrap();
+    assert_eq!((fs.usage().bytes, fs.usage().files), (0, 1));
+  }
+
+  #[test]
+  fn failed_ops_give_the_charge_back() {
+    let mut fs = MemFs::new();
+    fs.set_quota(Some(100), None);
+    let f = fs.create("/a").unwrap();
+    f.open().unwrap();
+    assert_eq!(f.insert_at(50, &[1; 60]), Err(FileError::InvalidSeek));
+    assert_eq!(fs.usage().bytes, 0);
+    fs.set_quota(None, None);
+    let f = fs.file_mut("/a").unwrap();
+    f.seek(std::io::SeekFrom::Start(1 << 60)).unwrap();
+    // Charged up front, then refused when the backend can't allocate it.
+    assert_eq!(f.write(b"x"), Err(FileError::OutOfSpace));
+    assert_eq!(fs.usage().bytes, 0);
+  }
+
+  #[test]
+  fn linked_data_counts_once() {
+    let mut fs = MemFs::new();
+    fs.set_quota(Some(10), None);
+    fs.insert("/a", File::new_with_data("a", b"123456")).unwrap();
+    fs.link("/a", "/b").unwrap();
+    assert_eq!(fs.usage().bytes, 6);
+    assert_eq!(append(&mut fs, "/b", b"7890"), Ok(4));
+    assert_eq!(fs.usage().bytes, 10);
+    assert_eq!(append(&mut fs, "/a", b"!"), Err(FileError::OutOfSpace));
+    fs.remove("/a").unwrap();
+    assert_eq!(fs.usage().bytes, 10);
+    fs.remove("/b").unwrap();
+    assert_eq!(fs.usage().bytes, 0);
+  }
+
+  #[test]
+  fn usage_is_unlimited_without_a_quota() {
+    let fs = MemFs::new();
+    let usage = fs.usage();
+    assert_eq!((usage.max_bytes, usage.bytes_free()), (None, None));
+    assert!(!usage.to_string().contains("quota"));
+  }
+
+  #[test]
+  fn disk_usage_sums_directories() {
+    let mut fs = MemFs::new();
+    fs.mkdir_all("/d/e").unwrap();
+    fs.insert("/d/a", File::new_with_data("a", b"abc")).unwrap();
+    fs.insert("/d/e/b", File::new_with_data("b", b"de")).unwrap();
+    assert_eq!(fs.disk_usage("/d"), Ok(5));
+    assert_eq!(fs.disk_usage("/d/a"), Ok(3));
+  }
+}
@@ -0,0 +1,186 @@
+use std::fmt;
+use std::fmt::Display;
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::{File, FileError};
+
+/// Versions share data at this granularity.
+const CHUNK_SIZE: usize = 4096;
+
+pub type VersionId = usize;
+
+/// An immutable copy of a `File`'s data; chunks equal to the previous version's are shared.
+#[derive(Debug,Clone)]
+pub struct Version {
+  pub id: VersionId,
+  pub label: String,
+  len: usize,
+  chunks: Vec<Arc<[u8]>>,
+}
+
+#[derive(Debug,Default)]
+pub struct History {
+  versions: Vec<Version>,
+}
+
+/// Byte ranges that differ between two versions, in offsets of the longer one.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Diff {
+  pub old_len: usize,
+  pub new_len: usize,
+  pub changed: Vec<Range<usize>>,
+}
+
+impl Version {
+  pub fn len(&self) -> usize {
+    self.len
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  pub fn to_vec(&self) -> Vec<u8> {
+    self.chunks.concat()
+  }
+
+  /// How many chunks are physically shared with `other`.
+  pub fn shared_chunks(&self, other: &Version) -> usize {
+    self.chunks.iter().zip(&other.chunks).filter(|(a, b)| Arc::ptr_eq(a, b)).count()
+  }
+}
+
+impl Display for Version {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "v{} {:?} ({} bytes)", self.id, self.label, self.len)
+  }
+}
+
+impl History {
+  fn push(&mut self, label: &str, data: &[u8]) -> VersionId {
+    let prev = self.versions.last().map(|v| v.chunks.as_slice()).unwrap_or(&[]);
+    let chunks = data.chunks(CHUNK_SIZE).enumerate().map(|(i, chunk)| match prev.get(i) {
+      Some(old) if **old == *chunk => Arc::clone(old),
+      _ => Arc::from(chunk),
+    }).collect();
+    let id = self.versions.len();
+    self.versions.push(Version { id, label: String::from(label), len: data.len(), chunks });
+    id
+  }
+
+  fn get(&self, id: VersionId) -> Result<&Version, FileError> {
+    self.versions.get(id).ok_or(FileError::NoSuchVersion(id))
+  }
+}
+
+fn diff(old: &Version, new: &Version) -> Diff {
+  let mut changed: Vec<Range<usize>> = Vec::new();
+  let mut mark = |range: Range<usize>| match changed.last_mut() {
+    Some(last) if last.end == range.start => last.end = range.end,
+    _ => changed.push(range),
+  };
+
+  for (i, (a, b)) in old.chunks.iter().zip(&new.chunks).enumerate() {
+    if Arc::ptr_eq(a, b) {
+      continue;
+    }
+    let base = i * CHUNK_SIZE;
+    let common = a.len().min(b.len());
+    for j in 0..common {
+      if a[j] != b[j] {
+        mark(base + j..base + j + 1);
+      }
+    }
+    if a.len() != b.len() {
+      mark(base + common..base + a.len().max(b.len()));
+    }
+  }
+  let tail_start = old.chunks.len().min(new.chunks.len()) * CHUNK_SIZE;
+  let long = old.len.max(new.len);
+  if long > tail_start {
+    mark(tail_start..long);
+  }
+
+  Diff { old_len: old.len, new_len: new.len, changed }
+}
+
+impl File {
+  /// Records the current data as a new version.
+  pub fn snapshot(&mut self, label: &str) -> VersionId {
+    self.history.push(label, &self.data.contents())
+  }
+
+  /// Every version taken so far, oldest first.
+  pub fn versions(&self) -> &[Version] {
+    &self.history.versions
+  }
+
+  /// Drops every version, e.g. before `encrypt`. Ids start again from 0.
+  pub fn clear_snapshots(&mut self) {
+    self.history.versions.clear();
+  }
+
+  /// Replaces the data with that of version `id`; later versions are kept.
+  /// Needs write permission, and is refused on deleted or encrypted files.
+  pub fn rollback(&mut self, id: VersionId) -> Result<(), FileError> {
+    let data = self.history.get(id)?.to_vec();
+    self.replace_data(&data)
+  }
+
+  pub fn diff(&self, old: VersionId, new: VersionId) -> Result<Diff, FileError> {
+    Ok(diff(self.history.get(old)?, self.history.get(new)?))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::perm::User;
+
+  #[test]
+  fn rollback_restores_an_old_version() {
+    let mut f = File::new_with_data("a.txt", b"first");
+    let v0 = f.snapshot("before");
+    f.open().unwrap();
+    f.write(b"FIRST, then more").unwrap();
+    let v1 = f.snapshot("after");
+    f.close().unwrap();
+    f.rollback(v0).unwrap();
+    assert_eq!(&*f.contents(), b"first");
+    assert_eq!(f.versions().len(), 2);
+    let diff = f.diff(v0, v1).unwrap();
+    assert_eq!((diff.old_len, diff.new_len), (5, 16));
+    assert_eq!(diff.changed.first(), Some(&(0..16)));
+    assert_eq!(f.rollback(7), Err(FileError::NoSuchVersion(7)));
+  }
+
+  #[test]
+  fn unchanged_chunks_are_shared() {
+    let mut f = File::new_with_data("big", &vec![7u8; CHUNK_SIZE * 3]);
+    f.snapshot("a");
+    f.open().unwrap();
+    f.write(b"x").unwrap();
+    f.snapshot("b");
+    let versions = f.versions();
+    assert_eq!(versions[1].shared_chunks(&versions[0]), 2);
+  }
+
+  #[test]
+  fn rollback_needs_write_permission() {
+    let mut f = File::new_with_data("a.txt", b"v0");
+    let v0 = f.snapshot("v0");
+    f.chown("alice", "staff").unwrap();
+    f.chmod(0o644).unwrap();
+    f.set_user(User::new("bob", &[]));
+    assert_eq!(f.rollback(v0), Err(FileError::PermissionDenied));
+  }
+
+  #[test]
+  fn rollback_refuses_deleted_files() {
+    let mut f = File::new_with_data("a.txt", b"v0");
+    let v0 = f.snapshot("v0");
+    f.delete().unwrap();
+    assert_eq!(f.rollback(v0), Err(FileError::NotFound));
+  }
+}
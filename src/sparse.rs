@@ -0,0 +1,323 @@
+//! Sparse files: data with holes that take no memory and read as zeros.
+//!
+//! On the `Sparse` backend, writing past the end or growing with `truncate`
+//! leaves a hole instead of filling in zeros. `seek_data` and `seek_hole` find
+//! where the holes are, and `stored_len` reports the bytes actually allocated.
+//! Quotas and `max_size` still count the logical length, as with compression.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Range;
+
+use crate::compress::Compression;
+use crate::state::FileEvent;
+use crate::storage::{check_range, resize_vec, Storage};
+use crate::{File, FileError};
+
+/// Zero runs at least this long become holes when dense data is made sparse.
+pub const MIN_HOLE: usize = 4096;
+
+/// A backend that only keeps the written runs of a file.
+#[derive(Default)]
+pub struct Sparse {
+  /// Allocated runs by offset; they never overlap or touch.
+  extents: BTreeMap<usize, Vec<u8>>,
+  len: usize,
+}
+
+impl fmt::Debug for Sparse {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("Sparse")
+      .field("len", &self.len)
+      .field("extents", &self.extents.len())
+      .field("allocated", &self.stored_len())
+      .finish()
+  }
+}
+
+impl Sparse {
+  pub fn new() -> Sparse {
+    Sparse::default()
+  }
+
+  /// Copies `data`, leaving out zero runs of at least `MIN_HOLE` bytes.
+  pub fn from_bytes(data: &[u8]) -> Sparse {
+    let mut sparse = Sparse { extents: BTreeMap::new(), len: data.len() };
+    let mut start = 0;
+    while start < data.len() {
+      let zeros = data[start..].iter().take_while(|&&b| b == 0).count();
+      if zeros >= MIN_HOLE || start + zeros == data.len() {
+        start += zeros;
+        continue;
+      }
+      // Data runs until the next zero run long enough to be a hole.
+      let mut end = start + zeros;
+      while end < data.len() {
+        let run = data[end..].iter().take_while(|&&b| b == 0).count();
+        if run >= MIN_HOLE || end + run == data.len() {
+          break;
+        }
+        end += run.max(1);
+      }
+      sparse.extents.insert(start, data[start..end].to_vec());
+      start = end;
+    }
+    sparse
+  }
+
+  /// The allocated runs, in order.
+  pub fn extents(&self) -> impl Iterator<Item = Range<usize>> + '_ {
+    self.extents.iter().map(|(&start, run)| start..start + run.len())
+  }
+
+  /// The extent holding `offset`, as its start and run.
+  fn extent_at(&self, offset: usize) -> Option<(usize, &Vec<u8>)> {
+    self.extents.range(..=offset).next_back()
+      .filter(|(start, run)| offset < *start + run.len())
+      .map(|(&start, run)| (start, run))
+  }
+
+  /// Splits the extent holding `pos`, if any, so that one starts at `pos`.
+  fn split_at(&mut self, pos: usize) {
+    if let Some((&start, run)) = self.extents.range_mut(..pos).next_back()
+      && pos < start + run.len()
+    {
+      let right = run.split_off(pos - start);
+      self.extents.insert(pos, right);
+    }
+  }
+
+  /// Moves every extent at or after `from` to start `by` bytes later, or
+  /// earlier if `back` is set.
+  fn shift(&mut self, from: usize, by: usize, back: bool) {
+    let tail = self.extents.split_off(&from);
+    for (start, run) in tail {
+      let start = if back { start - by } else { start + by };
+      self.extents.insert(start, run);
+    }
+  }
+
+  /// Puts `run` back at `start`, swallowing any later extents it now reaches.
+  fn absorb(&mut self, start: usize, mut run: Vec<u8>) -> Result<(), FileError> {
+    loop {
+      let end = start + run.len();
+      let Some(next) = self.extents.range(start..=end).next().map(|(&next, _)| next) else { break };
+      let later = self.extents.remove(&next).unwrap_or_default();
+      if next + later.len() > end {
+        let extra = &later[end - next..];
+        run.try_reserve(extra.len()).map_err(|_| FileError::OutOfSpace)?;
+        run.extend_from_slice(extra);
+      }
+    }
+    self.extents.insert(start, run);
+    Ok(())
+  }
+}
+
+impl Storage for Sparse {
+  fn len(&self) -> usize {
+    self.len
+  }
+
+  fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+    if offset >= self.len {
+      return 0;
+    }
+    let n = buf.len().min(self.len - offset);
+    let buf = &mut buf[..n];
+    buf.fill(0);
+    let first = self.extent_at(offset).map_or(offset, |(start, _)| start);
+    for (&start, run) in self.extents.range(first..offset + n) {
+      let from = offset.max(start);
+      let to = (offset + n).min(start + run.len());
+      buf[from - offset..to - offset].copy_from_slice(&run[from - start..to - start]);
+    }
+    n
+  }
+
+  fn write_at(&mut self, offset: usize, buf: &[u8]) -> Result<(), FileError> {
+    let end = offset.checked_add(buf.len()).ok_or(FileError::OutOfSpace)?;
+    self.len = self.len.max(end);
+    if buf.is_empty() {
+      return Ok(());
+    }
+    // Extend the extent that ends at or runs past `offset`, else start a new one.
+    let (start, mut run) = match self.extents.range(..=offset).next_back() {
+      Some((&start, run)) if start + run.len() >= offset => (start, self.extents.remove(&start).unwrap_or_default()),
+      _ => (offset, Vec::new()),
+    };
+    let at = offset - start;
+    if run.len() < at + buf.len() {
+      resize_vec(&mut run, at + buf.len())?;
+    }
+    run[at..at + buf.len()].copy_from_slice(buf);
+    self.absorb(start, run)
+  }
+
+  fn insert(&mut self, offset: usize, buf: &[u8]) -> Result<(), FileError> {
+    if offset > self.len {
+      return Err(FileError::InvalidSeek);
+    }
+    if buf.is_empty() {
+      return Ok(());
+    }
+    self.split_at(offset);
+    self.shift(offset, buf.len(), false);
+    self.len += buf.len();
+    self.write_at(offset, buf)
+  }
+
+  fn remove(&mut self, range: Range<usize>) -> Result<(), FileError> {
+    check_range(&range, self.len)?;
+    if range.is_empty() {
+      return Ok(());
+    }
+    self.split_at(range.start);
+    self.split_at(range.end);
+    let mut tail = self.extents.split_off(&range.start);
+    self.extents.append(&mut tail.split_off(&range.end));
+    self.shift(range.end, range.len(), true);
+    self.len -= range.len();
+    // The runs either side of the gap may now touch.
+    if let Some((&start, run)) = self.extents.range(..range.start).next_back()
+      && start + run.len() == range.start
+    {
+      let run = self.extents.remove(&start).unwrap_or_default();
+      self.absorb(start, run)?;
+    }
+    Ok(())
+  }
+
+  /// Growing adds a hole; shrinking frees whatever lies past `len`.
+  fn resize(&mut self, len: usize) -> Result<(), FileError> {
+    if len < self.len {
+      self.split_at(len);
+      self.extents.split_off(&len);
+    }
+    self.len = len;
+    Ok(())
+  }
+
+  fn stored_len(&self) -> usize {
+    self.extents.values().map(Vec::len).sum()
+  }
+
+  fn next_data(&self, offset: usize) -> Option<usize> {
+    if offset >= self.len {
+      return None;
+    }
+    if self.extent_at(offset).is_some() {
+      return Some(offset);
+    }
+    self.extents.range(offset..).next().map(|(&start, _)| start)
+  }
+
+  fn next_hole(&self, offset: usize) -> usize {
+    match self.extent_at(offset) {
+      Some((start, run)) => (start + run.len()).min(self.len),
+      None => offset.min(self.len),
+    }
+  }
+}
+
+impl File {
+  /// Moves to the first byte of data at or after `offset`, like `lseek` with
+  /// `SEEK_DATA`. Fails with `NoData` if only holes follow.
+  pub fn seek_data(&mut self, offset: u64) -> Result<u64, FileError> {
+    self.transition(FileEvent::Seek)?;
+    let pos = usize::try_from(offset).ok()
+      .and_then(|offset| self.data.next_data(offset))
+      .ok_or(FileError::NoData { offset })?;
+    self.pos = pos;
+    Ok(pos as u64)
+  }
+
+  /// Moves to the start of the first hole at or after `offset`, like `lseek`
+  /// with `SEEK_HOLE`; the end of the file counts as a hole. Fails with
+  /// `NoData` at or past the end.
+  pub fn seek_hole(&mut self, offset: u64) -> Result<u64, FileError> {
+    self.transition(FileEvent::Seek)?;
+    let pos = usize::try_from(offset).ok()
+      .filter(|&offset| offset < self.data.len())
+      .map(|offset| self.data.next_hole(offset))
+      .ok_or(FileError::NoData { offset })?;
+    self.pos = pos;
+    Ok(pos as u64)
+  }
+
+  /// Moves the data onto the `Sparse` backend, turning long zero runs into
+  /// holes; drops compression. Fails with `HardLinked` while other links share
+  /// the data.
+  pub fn make_sparse(&mut self) -> Result<(), FileError> {
+    self.ensure_unlinked()?;
+    self.unshare_storage(Box::new(Sparse::from_bytes(&self.data.contents())));
+    self.compression = Compression::None;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::storage::tests::matches_vec;
+
+  #[test]
+  fn behaves_like_a_vec() {
+    matches_vec(Box::new(Sparse::new()), 500);
+    let mut data = vec![0; 3 * MIN_HOLE];
+    data[MIN_HOLE + 7] = 1;
+    matches_vec(Box::new(Sparse::from_bytes(&data)), 500);
+  }
+
+  #[test]
+  fn holes_take_no_space() {
+    let mut s = Sparse::new();
+    s.write_at(10_000, b"tail").unwrap();
+    s.resize(1_000_000).unwrap();
+    assert_eq!((s.len(), s.stored_len()), (1_000_000, 4));
+    let mut buf = [9; 6];
+    assert_eq!(s.read_at(9_998, &mut buf), 6);
+    assert_eq!(&buf, b"\0\0tail");
+    s.resize(10_002).unwrap();
+    assert_eq!(s.extents().collect::<Vec<_>>(), vec![10_000..10_002]);
+  }
+
+  #[test]
+  fn from_bytes_keeps_short_zero_runs() {
+    let mut data = vec![1; 10];
+    data.extend(vec![0; MIN_HOLE - 1]);
+    data.extend([2; 10]);
+    data.extend(vec![0; MIN_HOLE]);
+    data.extend([3; 10]);
+    let s = Sparse::from_bytes(&data);
+    let at = 20 + MIN_HOLE - 1 + MIN_HOLE;
+    assert_eq!(s.extents().collect::<Vec<_>>(), [0..20 + MIN_HOLE - 1, at..at + 10]);
+    assert_eq!(s.contents(), data);
+  }
+
+  #[test]
+  fn seek_data_and_hole() {
+    let mut f = File::new("f");
+    f.make_sparse().unwrap();
+    f.open().unwrap();
+    f.seek(std::io::SeekFrom::Start(100)).unwrap();
+    f.write(b"data").unwrap();
+    f.truncate(200).unwrap();
+    assert_eq!(f.seek_data(0), Ok(100));
+    assert_eq!(f.seek_hole(100), Ok(104));
+    assert_eq!(f.seek_hole(0), Ok(0));
+    assert_eq!(f.seek_data(104), Err(FileError::NoData { offset: 104 }));
+    assert_eq!(f.seek_hole(200), Err(FileError::NoData { offset: 200 }));
+    assert_eq!(f.stored_len(), 4);
+  }
+
+  #[test]
+  fn make_sparse_refuses_linked_data() {
+    let mut a = File::new_with_data("a", &[0; 2 * MIN_HOLE]);
+    let b = a.hard_link("b").unwrap();
+    assert_eq!(a.make_sparse(), Err(FileError::HardLinked));
+    drop(b);
+    a.make_sparse().unwrap();
+    assert_eq!((a.len(), a.stored_len()), (2 * MIN_HOLE, 0));
+  }
+}
@@ -0,0 +1,207 @@
+use std::fmt;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::FileError;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum FileState {
+  /// Open for both reading and writing.
+  Open,
+  OpenRead,
+  OpenWrite,
+  /// Open for writing; every write goes to the end of the data.
+  OpenAppend,
+  Closed,
+  /// Closed and held under an exclusive lock by a `LockManager`, so only the
+  /// lock's holder can reach it. The holder may open or delete it as if it
+  /// were closed; releasing the lock closes it again.
+  Locked,
+  /// Terminal: nothing can be done with the file any more.
+  Deleted,
+}
+
+/// Everything that can be attempted on a `File`, fed through `FileState::next`.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum FileEvent {
+  Open,
+  OpenRead,
+  OpenWrite,
+  OpenAppend,
+  Close,
+  Read,
+  Write,
+  Seek,
+  Lock,
+  Unlock,
+  Delete,
+}
+
+/// One accepted state change, as kept in a `File`'s transition log.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Transition {
+  pub from: FileState,
+  pub event: FileEvent,
+  pub to: FileState,
+}
+
+impl Display for FileState {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+     match self {
+         FileState::Open => write!(f, "OPEN"),
+         FileState::OpenRead => write!(f, "OPEN_READ"),
+         FileState::OpenWrite => write!(f, "OPEN_WRITE"),
+         FileState::OpenAppend => write!(f, "OPEN_APPEND"),
+         FileState::Closed => write!(f, "CLOSED"),
+         FileState::Locked => write!(f, "LOCKED"),
+         FileState::Deleted => write!(f, "DELETED"),
+     }
+   }
+}
+
+impl FromStr for FileState {
+  type Err = FileError;
+
+  /// Parses the `Display` form back, e.g. `"OPEN_READ"`.
+  fn from_str(s: &str) -> Result<FileState, FileError> {
+    match s {
+      "OPEN" => Ok(FileState::Open),
+      "OPEN_READ" => Ok(FileState::OpenRead),
+      "OPEN_WRITE" => Ok(FileState::OpenWrite),
+      "OPEN_APPEND" => Ok(FileState::OpenAppend),
+      "CLOSED" => Ok(FileState::Closed),
+      "LOCKED" => Ok(FileState::Locked),
+      "DELETED" => Ok(FileState::Deleted),
+      _ => Err(FileError::Corrupt(format!("unknown file state {:?}", s))),
+    }
+  }
+}
+
+impl Display for FileEvent {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let name = match self {
+      FileEvent::Open => "open",
+      FileEvent::OpenRead => "open_read",
+      FileEvent::OpenWrite => "open_write",
+      FileEvent::OpenAppend => "open_append",
+      FileEvent::Close => "close",
+      FileEvent::Read => "read",
+      FileEvent::Write => "write",
+      FileEvent::Seek => "seek",
+      FileEvent::Lock => "lock",
+      FileEvent::Unlock => "unlock",
+      FileEvent::Delete => "delete",
+    };
+    write!(f, "{}", name)
+  }
+}
+
+impl Display for Transition {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{} --{}--> {}", self.from, self.event, self.to)
+  }
+}
+
+impl FileState {
+  /// The transition table: the state `event` leads to, or `None` if it is illegal here.
+  pub fn next(self, event: FileEvent) -> Option<FileState> {
+    use FileEvent as E;
+    use FileState as S;
+    match (self, event) {
+      (S::Closed, E::Open) => Some(S::Open),
+      (S::Closed, E::OpenRead) => Some(S::OpenRead),
+      (S::Closed, E::OpenWrite) => Some(S::OpenWrite),
+      (S::Closed, E::OpenAppend) => Some(S::OpenAppend),
+      (S::Closed, E::Delete) => Some(S::Deleted),
+      (S::Closed, E::Lock) => Some(S::Locked),
+
+      (S::Locked, E::Open) => Some(S::Open),
+      (S::Locked, E::OpenRead) => Some(S::OpenRead),
+      (S::Locked, E::OpenWrite) => Some(S::OpenWrite),
+      (S::Locked, E::OpenAppend) => Some(S::OpenAppend),
+      (S::Locked, E::Delete) => Some(S::Deleted),
+      (S::Locked, E::Unlock) => Some(S::Closed),
+
+      (S::Open | S::OpenRead | S::OpenWrite | S::OpenAppend, E::Close) => Some(S::Closed),
+      (S::Open | S::OpenRead | S::OpenWrite | S::OpenAppend, E::Seek) => Some(self),
+      (S::Open | S::OpenRead, E::Read) => Some(self),
+      (S::Open | S::OpenWrite | S::OpenAppend, E::Write) => Some(self),
+      _ => None,
+    }
+  }
+
+  /// Like `next`, but explains a rejected event with the most specific `FileError`.
+  pub fn apply(self, event: FileEvent) -> Result<FileState, FileError> {
+    if let Some(to) = self.next(event) {
+      return Ok(to);
+    }
+    Err(match (self, event) {
+      (FileState::Deleted, _) => FileError::NotFound,
+      (FileState::Closed | FileState::Locked, FileEvent::Close | FileEvent::Read | FileEvent::Write | FileEvent::Seek) => {
+        FileError::NotOpen
+      }
+      (_, FileEvent::Open | FileEvent::OpenRead | FileEvent::OpenWrite | FileEvent::OpenAppend) => {
+        FileError::AlreadyOpen
+      }
+      (from, event) => FileError::IllegalTransition { from, event },
+    })
+  }
+
+  pub fn is_open(self) -> bool {
+    matches!(self, FileState::Open | FileState::OpenRead | FileState::OpenWrite | FileState::OpenAppend)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn read_only_files_refuse_writes() {
+    let state = FileState::Closed.apply(FileEvent::OpenRead).unwrap();
+    assert_eq!(state.apply(FileEvent::Read), Ok(FileState::OpenRead));
+    assert_eq!(
+      state.apply(FileEvent::Write),
+      Err(FileError::IllegalTransition { from: FileState::OpenRead, event: FileEvent::Write })
+    );
+  }
+
+  #[test]
+  fn rejected_events_get_specific_errors() {
+    assert_eq!(FileState::Closed.apply(FileEvent::Read), Err(FileError::NotOpen));
+    assert_eq!(FileState::Open.apply(FileEvent::OpenWrite), Err(FileError::AlreadyOpen));
+    assert_eq!(FileState::Deleted.apply(FileEvent::Open), Err(FileError::NotFound));
+    assert_eq!(FileState::Open.apply(FileEvent::Delete).ok(), None);
+  }
+
+  #[test]
+  fn display_parses_back() {
+    for state in [FileState::Open, FileState::OpenAppend, FileState::Closed, FileState::Locked, FileState::Deleted] {
+      assert_eq!(state.to_string().parse::<FileState>(), Ok(state));
+    }
+    assert!("LOCKD".parse::<FileState>().is_err());
+  }
+
+  #[test]
+  fn only_closed_files_lock() {
+    let locked = FileState::Closed.apply(FileEvent::Lock).unwrap();
+    assert_eq!(locked, FileState::Locked);
+    assert_eq!(locked.apply(FileEvent::OpenWrite), Ok(FileState::OpenWrite));
+    assert_eq!(locked.apply(FileEvent::Read), Err(FileError::NotOpen));
+    assert_eq!(locked.apply(FileEvent::Unlock), Ok(FileState::Closed));
+    assert_eq!(
+      FileState::Open.apply(FileEvent::Lock),
+      Err(FileError::IllegalTransition { from: FileState::Open, event: FileEvent::Lock })
+    );
+    assert!(FileState::Closed.apply(FileEvent::Unlock).is_err());
+  }
+
+  #[test]
+  fn file_logs_transitions() {
+    let mut f = crate::File::new("a");
+    f.open_append().unwrap();
+    f.close().unwrap();
+    let log: Vec<String> = f.transitions().map(|t| t.to_string()).collect();
+    assert_eq!(log, ["CLOSED --open_append--> OPEN_APPEND", "OPEN_APPEND --close--> CLOSED"]);
+  }
+}
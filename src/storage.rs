@@ -0,0 +1,388 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::ops::Range;
+
+use crate::FileError;
+use crate::link::Linked;
+
+/// Where a `File` keeps its bytes. Offsets past `len` are zero-filled on write.
+pub trait Storage: fmt::Debug + Send + Sync {
+  fn len(&self) -> usize;
+
+  fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /// Copies bytes starting at `offset` into `buf`, returning how many were available.
+  fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize;
+
+  /// Overwrites bytes at `offset`, growing the storage as needed.
+  fn write_at(&mut self, offset: usize, buf: &[u8]) -> Result<(), FileError>;
+
+  /// Inserts bytes at `offset`, shifting everything after it.
+  fn insert(&mut self, offset: usize, buf: &[u8]) -> Result<(), FileError>;
+
+  /// Removes `range`, shifting everything after it.
+  fn remove(&mut self, range: Range<usize>) -> Result<(), FileError>;
+
+  /// Shrinks or zero-extends to `len`.
+  fn resize(&mut self, len: usize) -> Result<(), FileError>;
+
+  /// The whole data as one slice, if the backend keeps it contiguous.
+  fn as_slice(&self) -> Option<&[u8]> {
+    None
+  }
+
+  /// The whole data, borrowed when contiguous and copied otherwise.
+  fn contents(&self) -> Cow<'_, [u8]> {
+    match self.as_slice() {
+      Some(slice) => Cow::Borrowed(slice),
+      None => {
+        let mut buf = vec![0; self.len()];
+        self.read_at(0, &mut buf);
+        Cow::Owned(buf)
+      }
+    }
+  }
+
+  /// Bytes actually held in memory, which differs from `len` for packed backends.
+  fn stored_len(&self) -> usize {
+    self.len()
+  }
+
+  /// The first offset at or after `offset` that isn't in a hole, or `None` if
+  /// only holes follow; see `sparse`. Dense backends have no holes.
+  fn next_data(&self, offset: usize) -> Option<usize> {
+    (offset < self.len()).then_some(offset)
+  }
+
+  /// The first offset at or after `offset` in a hole, counting the end as one.
+  fn next_hole(&self, offset: usize) -> usize {
+    self.len().max(offset)
+  }
+
+  /// Identifies the stored bytes when other files may hold the very same ones,
+  /// as deduplicated and hard linked data do, so they are counted once.
+  fn shared_id(&self) -> Option<usize> {
+    None
+  }
+
+  /// The shared handle, if this data is hard linked; see `link`.
+  fn as_linked(&self) -> Option<&Linked> {
+    None
+  }
+}
+
+pub(crate) fn resize_vec(data: &mut Vec<u8>, len: usize) -> Result<(), FileError> {
+  if len > data.len() {
+    data.try_reserve(len - data.len()).map_err(|_| FileError::OutOfSpace)?;
+  }
+  data.resize(len, 0);
+  Ok(())
+}
+
+pub(crate) fn check_range(range: &Range<usize>, len: usize) -> Result<(), FileError> {
+  if range.start > range.end || range.end > len {
+    return Err(FileError::InvalidSeek);
+  }
+  Ok(())
+}
+
+/// The plain contiguous backend, and the default for new files.
+impl Storage for Vec<u8> {
+  fn len(&self) -> usize {
+    Vec::len(self)
+  }
+
+  fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+    let start = offset.min(Vec::len(self));
+    let n = buf.len().min(Vec::len(self) - start);
+    buf[..n].copy_from_slice(&self[start..start + n]);
+    n
+  }
+
+  fn write_at(&mut self, offset: usize, buf: &[u8]) -> Result<(), FileError> {
+    let end = offset.checked_add(buf.len()).ok_or(FileError::OutOfSpace)?;
+    if end > Vec::len(self) {
+      resize_vec(self, end)?;
+    }
+    self[offset..end].copy_from_slice(buf);
+    Ok(())
+  }
+
+  fn insert(&mut self, offset: usize, buf: &[u8]) -> Result<(), FileError> {
+    if offset > Vec::len(self) {
+      return Err(FileError::InvalidSeek);
+    }
+    self.try_reserve(buf.len()).map_err(|_| FileError::OutOfSpace)?;
+    self.splice(offset..offset, buf.iter().copied());
+    Ok(())
+  }
+
+  fn remove(&mut self, range: Range<usize>) -> Result<(), FileError> {
+    check_range(&range, Vec::len(self))?;
+    self.drain(range);
+    Ok(())
+  }
+
+  fn resize(&mut self, len: usize) -> Result<(), FileError> {
+    resize_vec(self, len)
+  }
+
+  fn as_slice(&self) -> Option<&[u8]> {
+    Some(self)
+  }
+}
+
+/// Pieces larger than this are split, so edits only ever move one piece's bytes.
+const MAX_PIECE: usize = 64 * 1024;
+
+/// A chunked backend for large files: inserting or removing in the middle only
+/// moves the bytes of the pieces involved, and lookups binary-search the piece
+/// offsets. The offsets are rebuilt after every edit, which costs O(pieces)
+/// but no byte copying.
+#[derive(Default)]
+pub struct Rope {
+  pieces: Vec<Vec<u8>>,
+  /// `starts[i]` is the offset of `pieces[i]`; kept in step with `pieces`.
+  starts: Vec<usize>,
+  len: usize,
+}
+
+impl fmt::Debug for Rope {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("Rope")
+      .field("len", &self.len)
+      .field("pieces", &self.pieces.len())
+      .finish()
+  }
+}
+
+impl Rope {
+  pub fn new() -> Rope {
+    Rope::default()
+  }
+
+  pub fn from_bytes(data: &[u8]) -> Rope {
+    let mut rope = Rope::new();
+    rope.pieces = data.chunks(MAX_PIECE / 2).map(|c| c.to_vec()).collect();
+    rope.reindex();
+    rope
+  }
+
+  /// Drops empty pieces and recomputes `starts` and `len` from the pieces.
+  fn reindex(&mut self) {
+    self.pieces.retain(|p| !p.is_empty());
+    self.starts.clear();
+    let mut offset = 0;
+    for piece in &self.pieces {
+      self.starts.push(offset);
+      offset += piece.len();
+    }
+    self.len = offset;
+  }
+
+  /// The piece holding `offset` and the position inside it; `offset == len` maps
+  /// to the end of the last piece.
+  fn locate(&self, offset: usize) -> (usize, usize) {
+    match self.starts.binary_search(&offset) {
+      Ok(i) => (i, 0),
+      Err(i) => {
+        let i = i.saturating_sub(1);
+        (i, offset - self.starts.get(i).copied().unwrap_or(0))
+      }
+    }
+  }
+}
+
+impl Storage for Rope {
+  fn len(&self) -> usize {
+    self.len
+  }
+
+  fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+    if offset >= self.len {
+      return 0;
+    }
+    let (mut i, mut inner) = self.locate(offset);
+    let mut n = 0;
+    while n < buf.len() && i < self.pieces.len() {
+      let piece = &self.pieces[i][inner..];
+      let take = piece.len().min(buf.len() - n);
+      buf[n..n + take].copy_from_slice(&piece[..take]);
+      n += take;
+      i += 1;
+      inner = 0;
+    }
+    n
+  }
+
+  fn write_at(&mut self, offset: usize, buf: &[u8]) -> Result<(), FileError> {
+    if offset > self.len {
+      self.resize(offset)?;
+    }
+    let overlap = buf.len().min(self.len - offset);
+    if overlap > 0 {
+      let (mut i, mut inner) = self.locate(offset);
+      let mut n = 0;
+      while n < overlap {
+        let piece = &mut self.pieces[i][inner..];
+        let take = piece.len().min(overlap - n);
+        piece[..take].copy_from_slice(&buf[n..n + take]);
+        n += take;
+        i += 1;
+        inner = 0;
+      }
+    }
+    if overlap < buf.len() {
+      let len = self.len;
+      self.insert(len, &buf[overlap..])?;
+    }
+    Ok(())
+  }
+
+  fn insert(&mut self, offset: usize, buf: &[u8]) -> Result<(), FileError> {
+    if offset > self.len {
+      return Err(FileError::InvalidSeek);
+    }
+    if buf.is_empty() {
+      return Ok(());
+    }
+    if self.pieces.is_empty() {
+      self.pieces = buf.chunks(MAX_PIECE / 2).map(|c| c.to_vec()).collect();
+      self.reindex();
+      return Ok(());
+    }
+    let (i, inner) = self.locate(offset);
+    if self.pieces[i].len() + buf.len() <= MAX_PIECE {
+      self.pieces[i].splice(inner..inner, buf.iter().copied());
+    } else {
+      let right = self.pieces[i].split_off(inner);
+      let new_pieces = buf.chunks(MAX_PIECE / 2).map(|c| c.to_vec()).chain(Some(right));
+      self.pieces.splice(i + 1..i + 1, new_pieces);
+    }
+    self.reindex();
+    Ok(())
+  }
+
+  fn remove(&mut self, range: Range<usize>) -> Result<(), FileError> {
+    check_range(&range, self.len)?;
+    if range.is_empty() {
+      return Ok(());
+    }
+    let (mut i, inner) = self.locate(range.start);
+    let mut left = range.end - range.start;
+    let mut from = inner;
+    while left > 0 {
+      let piece = &mut self.pieces[i];
+      let take = (piece.len() - from).min(left);
+      piece.drain(from..from + take);
+      left -= take;
+      i += 1;
+      from = 0;
+    }
+    self.reindex();
+    Ok(())
+  }
+
+  fn resize(&mut self, len: usize) -> Result<(), FileError> {
+    if len < self.len {
+      return self.remove(len..self.len);
+    }
+    let mut missing = len - self.len;
+    let count = missing.div_ceil(MAX_PIECE / 2);
+    self.pieces.try_reserve(count).map_err(|_| FileError::OutOfSpace)?;
+    self.starts.try_reserve(count).map_err(|_| FileError::OutOfSpace)?;
+    let old = self.pieces.len();
+    while missing > 0 {
+      let mut zeros = Vec::new();
+      if let Err(e) = resize_vec(&mut zeros, missing.min(MAX_PIECE / 2)) {
+        self.pieces.truncate(old);
+        return Err(e);
+      }
+      missing -= zeros.len();
+      self.pieces.push(zeros);
+    }
+    self.reindex();
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+
+  /// Runs the same pseudo-random edits on `storage` and a plain `Vec`,
+  /// checking they agree after each one.
+  pub(crate) fn matches_vec(mut storage: Box<dyn Storage>, rounds: usize) {
+    let mut model: Vec<u8> = storage.contents().into_owned();
+    let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+    let mut next = |n: usize| {
+      seed ^= seed << 13;
+      seed ^= seed >> 7;
+      seed ^= seed << 17;
+      (seed % (n as u64 + 1)) as usize
+    };
+    for round in 0..rounds {
+      let len = model.len();
+      let offset = next(len + 100);
+      let buf = vec![round as u8 | 1; next(3000)];
+      match next(3) {
+        0 => {
+          storage.write_at(offset, &buf).unwrap();
+          if model.len() < offset + buf.len() {
+            model.resize(offset + buf.len(), 0);
+          }
+          model[offset..offset + buf.len()].copy_from_slice(&buf);
+        }
+        1 => {
+          let offset = offset.min(len);
+          storage.insert(offset, &buf).unwrap();
+          model.splice(offset..offset, buf);
+        }
+        2 => {
+          let start = offset.min(len);
+          let end = (start + next(5000)).min(len);
+          storage.remove(start..end).unwrap();
+          model.drain(start..end);
+        }
+        _ => {
+          storage.resize(offset).unwrap();
+          model.resize(offset, 0);
+        }
+      }
+      assert_eq!(storage.len(), model.len());
+      assert_eq!(&*storage.contents(), &model[..], "after round {}", round);
+    }
+  }
+
+  #[test]
+  fn rope_behaves_like_vec() {
+    matches_vec(Box::new(Rope::from_bytes(&[5u8; 100_000])), 300);
+  }
+
+  #[test]
+  fn rope_keeps_pieces_small() {
+    let mut rope = Rope::from_bytes(&[1u8; 10]);
+    rope.insert(5, &vec![2u8; MAX_PIECE * 3]).unwrap();
+    assert!(rope.pieces.iter().all(|p| p.len() <= MAX_PIECE));
+    assert_eq!(rope.len(), MAX_PIECE * 3 + 10);
+  }
+
+  #[test]
+  fn huge_resizes_fail_cleanly() {
+    let mut rope = Rope::from_bytes(b"abc");
+    assert_eq!(rope.resize(1 << 60), Err(FileError::OutOfSpace));
+    assert_eq!(rope.len(), 3);
+    let mut data = b"abc".to_vec();
+    assert_eq!(Storage::resize(&mut data, 1 << 60), Err(FileError::OutOfSpace));
+  }
+
+  #[test]
+  fn vec_rejects_bad_ranges() {
+    let mut data = b"abc".to_vec();
+    assert!(Storage::remove(&mut data, 2..5).is_err());
+    assert!(Storage::insert(&mut data, 4, b"x").is_err());
+    assert_eq!(data, b"abc");
+  }
+}
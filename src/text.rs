@@ -0,0 +1,247 @@
+use std::borrow::Cow;
+use std::str;
+
+use crate::view::View;
+use crate::{File, FileError};
+
+/// Lines split on `\n` with any trailing `\r` removed. A line that isn't valid
+/// UTF-8 yields an error and iteration carries on with the next line.
+#[derive(Debug,Clone)]
+pub struct Lines<'a> {
+  split: Split<'a>,
+  line: usize,
+}
+
+/// Segments between occurrences of a delimiter byte, without the delimiter.
+#[derive(Debug,Clone)]
+pub struct Split<'a> {
+  rest: &'a [u8],
+  offset: usize,
+  delim: u8,
+}
+
+/// CSV records per RFC 4180: quoted fields may hold the delimiter, `""` and
+/// line breaks. Unquoted fields are borrowed; quoted ones are unescaped copies.
+#[derive(Debug,Clone)]
+pub struct CsvRecords<'a> {
+  rest: &'a [u8],
+  offset: usize,
+  line: usize,
+  delim: u8,
+}
+
+fn utf8(bytes: &[u8], line: usize, offset: usize) -> Result<&str, FileError> {
+  str::from_utf8(bytes).map_err(|e| FileError::InvalidUtf8 { line, offset: offset + e.valid_up_to() })
+}
+
+impl<'a> Split<'a> {
+  /// The next segment and its offset in the data.
+  fn next_segment(&mut self) -> Option<(usize, &'a [u8])> {
+    if self.rest.is_empty() {
+      return None;
+    }
+    let start = self.offset;
+    let (segment, consumed) = match self.rest.iter().position(|&b| b == self.delim) {
+      Some(i) => (&self.rest[..i], i + 1),
+      None => (self.rest, self.rest.len()),
+    };
+    self.rest = &self.rest[consumed..];
+    self.offset += consumed;
+    Some((start, segment))
+  }
+}
+
+impl<'a> Iterator for Split<'a> {
+  type Item = &'a [u8];
+
+  fn next(&mut self) -> Option<&'a [u8]> {
+    self.next_segment().map(|(_, segment)| segment)
+  }
+}
+
+impl<'a> Iterator for Lines<'a> {
+  type Item = Result<&'a str, FileError>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    let (offset, line) = self.split.next_segment()?;
+    self.line += 1;
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    Some(utf8(line, self.line, offset))
+  }
+}
+
+impl<'a> CsvRecords<'a> {
+  fn corrupt(line: usize, what: &str) -> FileError {
+    FileError::Corrupt(format!("csv line {}: {}", line, what))
+  }
+
+  /// Parses one field starting at the front of `rest`, leaving `rest` on the
+  /// delimiter or line break that ended it.
+  fn field(&mut self) -> Result<Cow<'a, str>, FileError> {
+    let (line, offset) = (self.line, self.offset);
+    if self.rest.first() != Some(&b'"') {
+      let end = self.rest.iter().position(|&b| b == self.delim || b == b'\n').unwrap_or(self.rest.len());
+      let raw = &self.rest[..end];
+      let raw = raw.strip_suffix(b"\r").unwrap_or(raw);
+      self.rest = &self.rest[end..];
+      self.offset += end;
+      return utf8(raw, line, offset).map(Cow::Borrowed);
+    }
+
+    let mut value = Vec::new();
+    let mut i = 1;
+    loop {
+      match self.rest.get(i) {
+        None => {
+          // `rest` stays put, so forget the line breaks counted inside the quotes.
+          self.line = line;
+          return Err(Self::corrupt(line, "unterminated quoted field"));
+        }
+        Some(b'"') if self.rest.get(i + 1) == Some(&b'"') => {
+          value.push(b'"');
+          i += 2;
+        }
+        Some(b'"') => {
+          i += 1;
+          break;
+        }
+        Some(&b) => {
+          if b == b'\n' {
+            self.line += 1;
+          }
+          value.push(b);
+          i += 1;
+        }
+      }
+    }
+    self.rest = &self.rest[i..];
+    self.offset += i;
+    match self.rest.first() {
+      None | Some(b'\n') => {}
+      Some(&b) if b == self.delim => {}
+      Some(b'\r') if self.rest.get(1).is_none_or(|&b| b == b'\n') => {
+        self.rest = &self.rest[1..];
+        self.offset += 1;
+      }
+      Some(_) => return Err(Self::corrupt(self.line, "text after closing quote")),
+    }
+    String::from_utf8(value)
+      .map(Cow::Owned)
+      .map_err(|e| FileError::InvalidUtf8 { line, offset: offset + 1 + e.utf8_error().valid_up_to() })
+  }
+
+  fn record(&mut self) -> Result<Vec<Cow<'a, str>>, FileError> {
+    let mut fields = vec![self.field()?];
+    while self.rest.first() == Some(&self.delim) {
+      self.rest = &self.rest[1..];
+      self.offset += 1;
+      fields.push(self.field()?);
+    }
+    Ok(fields)
+  }
+
+  /// After an error, resumes at the start of the next physical line.
+  fn skip_line(&mut self) {
+    let end = self.rest.iter().position(|&b| b == b'\n').map(|i| i + 1).unwrap_or(self.rest.len());
+    self.rest = &self.rest[end..];
+    self.offset += end;
+  }
+}
+
+impl<'a> Iterator for CsvRecords<'a> {
+  type Item = Result<Vec<Cow<'a, str>>, FileError>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.rest.is_empty() {
+      return None;
+    }
+    self.line += 1;
+    let record = self.record();
+    if record.is_err() {
+      self.skip_line();
+    } else if self.rest.first() == Some(&b'\n') {
+      self.rest = &self.rest[1..];
+      self.offset += 1;
+    }
+    Some(record)
+  }
+}
+
+impl<'a> View<'a> {
+  pub fn lines(&self) -> Lines<'a> {
+    Lines { split: self.split(b'\n'), line: 0 }
+  }
+
+  pub fn split(&self, delim: u8) -> Split<'a> {
+    Split { rest: self.as_bytes(), offset: 0, delim }
+  }
+
+  pub fn csv_records(&self) -> CsvRecords<'a> {
+    self.csv_records_with(b',')
+  }
+
+  pub fn csv_records_with(&self, delim: u8) -> CsvRecords<'a> {
+    CsvRecords { rest: self.as_bytes(), offset: 0, line: 0, delim }
+  }
+}
+
+impl File {
+  /// See `View::lines`; like `view`, needs a contiguous backend.
+  pub fn lines(&self) -> Result<Lines<'_>, FileError> {
+    Ok(self.view()?.lines())
+  }
+
+  pub fn split(&self, delim: u8) -> Result<Split<'_>, FileError> {
+    Ok(self.view()?.split(delim))
+  }
+
+  pub fn csv_records(&self) -> Result<CsvRecords<'_>, FileError> {
+    Ok(self.view()?.csv_records())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn lines_strip_cr_and_survive_bad_utf8() {
+    let data = b"one\r\ntw\xffo\nthree";
+    let lines: Vec<_> = View::new(data).lines().collect();
+    assert_eq!(lines, [Ok("one"), Err(FileError::InvalidUtf8 { line: 2, offset: 7 }), Ok("three")]);
+  }
+
+  #[test]
+  fn split_keeps_empty_segments() {
+    let parts: Vec<&[u8]> = View::new(b"a::b:").split(b':').collect();
+    assert_eq!(parts, [&b"a"[..], b"", b"b"]);
+  }
+
+  #[test]
+  fn csv_quoting() {
+    let data = b"name,quote\r\n\"Smith, J\",\"said \"\"hi\"\"\nthen left\"\nplain,";
+    let records: Vec<Vec<String>> = View::new(data).csv_records()
+      .map(|r| r.unwrap().into_iter().map(Cow::into_owned).collect())
+      .collect();
+    assert_eq!(records, [
+      vec!["name", "quote"],
+      vec!["Smith, J", "said \"hi\"\nthen left"],
+      vec!["plain", ""],
+    ]);
+  }
+
+  #[test]
+  fn csv_errors_report_the_right_line() {
+    let data = b"a,\"b\"x\nc,\"open\nd\ne";
+    let mut records = View::new(data).csv_records();
+    assert_eq!(records.next(), Some(Err(FileError::Corrupt("csv line 1: text after closing quote".into()))));
+    assert_eq!(records.next(), Some(Err(FileError::Corrupt("csv line 2: unterminated quoted field".into()))));
+    // Parsing resumes on the line after the bad one, counted from where it started.
+    let next = records.next().unwrap().unwrap();
+    assert_eq!(next, ["d"]);
+    let data = b"\"open\nx\xff";
+    let mut records = View::new(data).csv_records();
+    assert!(records.next().unwrap().is_err());
+    assert_eq!(records.next(), Some(Err(FileError::InvalidUtf8 { line: 2, offset: 7 })));
+  }
+}
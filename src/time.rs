@@ -0,0 +1,104 @@
+//! When a `File` was created, last modified and last read, and how those times print.
+//!
+//! `modified` moves on every change to the data and `accessed` on every `read`;
+//! borrowing reads such as `view` and `contents` leave it alone.
+
+use std::fmt;
+use std::fmt::Display;
+use std::ops::RangeBounds;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::memfs::MemFs;
+use crate::File;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Timestamps {
+  pub created: SystemTime,
+  pub modified: SystemTime,
+  pub accessed: SystemTime,
+}
+
+/// Prints a time as RFC 3339 in UTC to the second, e.g. `2024-05-01T13:45:00Z`.
+/// Times before the epoch print as the epoch.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Rfc3339(pub SystemTime);
+
+impl Default for Timestamps {
+  fn default() -> Timestamps {
+    let now = SystemTime::now();
+    Timestamps { created: now, modified: now, accessed: now }
+  }
+}
+
+/// Whole seconds since the epoch, as tar headers store them; earlier times give 0.
+pub fn unix_secs(time: SystemTime) -> u64 {
+  time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// `None` past the latest time `SystemTime` can hold.
+pub fn from_unix_secs(secs: u64) -> Option<SystemTime> {
+  UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+/// Year, month and day of the `days`th day after 1970-01-01, in the proleptic
+/// Gregorian calendar (Howard Hinnant's `civil_from_days`).
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+  let z = days + 719_468;
+  let era = z / 146_097;
+  let doe = z % 146_097;
+  let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = doy - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  let year = yoe + era * 400 + u64::from(month <= 2);
+  (year, month, day)
+}
+
+impl Display for Rfc3339 {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let secs = unix_secs(self.0);
+    let (year, month, day) = civil_from_days(secs / 86_400);
+    let rem = secs % 86_400;
+    write!(f, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, rem / 3600, rem / 60 % 60, rem % 60)
+  }
+}
+
+impl File {
+  pub fn timestamps(&self) -> &Timestamps {
+    &self.times
+  }
+
+  /// Overrides the times, e.g. to restore them from an archive.
+  pub fn set_timestamps(&mut self, times: Timestamps) {
+    self.times = times;
+  }
+}
+
+impl MemFs {
+  /// Files last modified within `range`, e.g. `since..` or `..before`.
+  pub fn modified_in(&self, range: impl RangeBounds<SystemTime>) -> Vec<(String, &File)> {
+    self.find(|file| range.contains(&file.times.modified))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn rfc3339_dates() {
+    let at = |secs| Rfc3339(from_unix_secs(secs).unwrap()).to_string();
+    assert_eq!(at(0), "1970-01-01T00:00:00Z");
+    assert_eq!(at(951_782_400), "2000-02-29T00:00:00Z");
+    assert_eq!(at(1_714_571_100), "2024-05-01T13:45:00Z");
+    assert_eq!(Rfc3339(UNIX_EPOCH - Duration::from_secs(1)).to_string(), "1970-01-01T00:00:00Z");
+  }
+
+  #[test]
+  fn unix_secs_round_trip() {
+    let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    assert_eq!(from_unix_secs(unix_secs(time)), Some(time));
+    assert_eq!(from_unix_secs(u64::MAX), None);
+  }
+}
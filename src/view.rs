@@ -0,0 +1,231 @@
+use std::ops::Range;
+
+use crate::compress::Compression;
+use crate::perm::Access;
+use crate::{File, FileError};
+
+/// A borrowed, bounds-checked window onto a `File`'s data.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct View<'a> {
+  bytes: &'a [u8],
+}
+
+/// Reads consecutive values out of a `View`, advancing past each one.
+#[derive(Debug,Clone)]
+pub struct Cursor<'a> {
+  view: View<'a>,
+  pos: usize,
+}
+
+/// Consecutive fixed-size records; a short trailing record is left in `remainder`.
+#[derive(Debug,Clone)]
+pub struct Records<'a> {
+  chunks: std::slice::ChunksExact<'a, u8>,
+}
+
+macro_rules! typed_reads {
+  ($($le:ident, $be:ident => $ty:ty;)*) => {
+    impl<'a> View<'a> {
+      $(
+        pub fn $le(&self, offset: usize) -> Result<$ty, FileError> {
+          Ok(<$ty>::from_le_bytes(self.array(offset)?))
+        }
+
+        pub fn $be(&self, offset: usize) -> Result<$ty, FileError> {
+          Ok(<$ty>::from_be_bytes(self.array(offset)?))
+        }
+      )*
+    }
+
+    impl<'a> Cursor<'a> {
+      $(
+        pub fn $le(&mut self) -> Result<$ty, FileError> {
+          let value = self.view.$le(self.pos)?;
+          self.pos += size_of::<$ty>();
+          Ok(value)
+        }
+
+        pub fn $be(&mut self) -> Result<$ty, FileError> {
+          let value = self.view.$be(self.pos)?;
+          self.pos += size_of::<$ty>();
+          Ok(value)
+        }
+      )*
+    }
+  };
+}
+
+typed_reads! {
+  u16_le, u16_be => u16;
+  u32_le, u32_be => u32;
+  u64_le, u64_be => u64;
+  i16_le, i16_be => i16;
+  i32_le, i32_be => i32;
+  i64_le, i64_be => i64;
+}
+
+impl<'a> View<'a> {
+  pub fn new(bytes: &'a [u8]) -> View<'a> {
+    View { bytes }
+  }
+
+  pub fn len(&self) -> usize {
+    self.bytes.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.bytes.is_empty()
+  }
+
+  pub fn as_bytes(&self) -> &'a [u8] {
+    self.bytes
+  }
+
+  pub fn bytes(&self, offset: usize, len: usize) -> Result<&'a [u8], FileError> {
+    let end = offset.checked_add(len).filter(|&end| end <= self.bytes.len());
+    match end {
+      Some(end) => Ok(&self.bytes[offset..end]),
+      None => Err(FileError::OutOfBounds { offset, len, size: self.bytes.len() }),
+    }
+  }
+
+  /// A reversed range is out of bounds too, reported with a `len` of 0.
+  pub fn slice(&self, range: Range<usize>) -> Result<View<'a>, FileError> {
+    let Some(len) = range.end.checked_sub(range.start) else {
+      return Err(FileError::OutOfBounds { offset: range.start, len: 0, size: self.bytes.len() });
+    };
+    Ok(View::new(self.bytes(range.start, len)?))
+  }
+
+  pub fn u8_at(&self, offset: usize) -> Result<u8, FileError> {
+    Ok(self.bytes(offset, 1)?[0])
+  }
+
+  fn array<const N: usize>(&self, offset: usize) -> Result<[u8; N], FileError> {
+    let mut out = [0u8; N];
+    out.copy_from_slice(self.bytes(offset, N)?);
+    Ok(out)
+  }
+
+  pub fn cursor(&self) -> Cursor<'a> {
+    Cursor { view: *self, pos: 0 }
+  }
+
+  /// Splits the view into `size`-byte records; a `size` of zero yields none.
+  pub fn records(&self, size: usize) -> Records<'a> {
+    let bytes = if size == 0 { &self.bytes[..0] } else { self.bytes };
+    Records { chunks: bytes.chunks_exact(size.max(1)) }
+  }
+}
+
+impl<'a> Cursor<'a> {
+  pub fn position(&self) -> usize {
+    self.pos
+  }
+
+  pub fn remaining(&self) -> usize {
+    self.view.len().saturating_sub(self.pos)
+  }
+
+  pub fn skip(&mut self, n: usize) -> Result<(), FileError> {
+    self.bytes(n).map(|_| ())
+  }
+
+  pub fn u8(&mut self) -> Result<u8, FileError> {
+    Ok(self.bytes(1)?[0])
+  }
+
+  pub fn bytes(&mut self, n: usize) -> Result<&'a [u8], FileError> {
+    let bytes = self.view.bytes(self.pos, n)?;
+    self.pos += n;
+    Ok(bytes)
+  }
+}
+
+impl<'a> Records<'a> {
+  pub fn remainder(&self) -> &'a [u8] {
+    self.chunks.remainder()
+  }
+}
+
+impl<'a> Iterator for Records<'a> {
+  type Item = View<'a>;
+
+  fn next(&mut self) -> Option<View<'a>> {
+    self.chunks.next().map(View::new)
+  }
+
+  fn size_hint(&self) -> (usize, Option<usize>) {
+    self.chunks.size_hint()
+  }
+}
+
+impl File {
+  /// Borrows the data without copying. Needs a contiguous backend; call
+  /// `make_contiguous` first for `Rope`, compressed or hard linked files.
+  pub fn view(&self) -> Result<View<'_>, FileError> {
+    self.ensure_plaintext()?;
+    self.check_access(Access::Read)?;
+    self.data.as_slice().map(View::new).ok_or(FileError::NotContiguous)
+  }
+
+  /// Moves the data into a plain `Vec` so it can be viewed; drops compression.
+  /// Fails with `HardLinked` while other links share the data.
+  pub fn make_contiguous(&mut self) -> Result<(), FileError> {
+    self.ensure_unlinked()?;
+    if self.data.as_slice().is_none() {
+      self.unshare_storage(Box::new(self.data.contents().into_owned()));
+      self.compression = Compression::None;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::storage::Rope;
+
+  #[test]
+  fn typed_reads_and_bounds() {
+    let view = View::new(&[1, 0, 0, 0, 0xff, 0xfe]);
+    assert_eq!(view.u32_le(0), Ok(1));
+    assert_eq!(view.u16_be(4), Ok(0xfffe));
+    assert_eq!(view.i16_le(4), Ok(-257));
+    assert_eq!(view.u32_le(4), Err(FileError::OutOfBounds { offset: 4, len: 4, size: 6 }));
+    assert!(view.bytes(usize::MAX, 2).is_err());
+  }
+
+  #[test]
+  fn slices_check_their_range() {
+    let view = View::new(b"abcdef");
+    assert_eq!(view.slice(1..3).unwrap().as_bytes(), b"bc");
+    assert!(view.slice(4..8).is_err());
+    let (start, end) = (4, 2);
+    assert_eq!(view.slice(start..end), Err(FileError::OutOfBounds { offset: 4, len: 0, size: 6 }));
+  }
+
+  #[test]
+  fn cursor_and_records() {
+    let view = View::new(&[1, 2, 0, 3, 0, 9]);
+    let mut cursor = view.cursor();
+    assert_eq!(cursor.u8(), Ok(1));
+    assert_eq!(cursor.u16_be(), Ok(0x0200));
+    assert_eq!(cursor.remaining(), 3);
+    assert!(cursor.bytes(4).is_err());
+    let records = view.records(4);
+    assert_eq!(records.remainder(), &[0, 9]);
+    assert_eq!(records.count(), 1);
+    assert_eq!(view.records(0).count(), 0);
+  }
+
+  #[test]
+  fn views_need_contiguous_plaintext() {
+    let mut f = File::new_with_storage("big", Rope::from_bytes(b"rope data"));
+    assert_eq!(f.view().err(), Some(FileError::NotContiguous));
+    f.make_contiguous().unwrap();
+    assert_eq!(f.view().unwrap().as_bytes(), b"rope data");
+    let _link = f.hard_link("b").unwrap();
+    assert_eq!(f.make_contiguous(), Err(FileError::HardLinked));
+  }
+}
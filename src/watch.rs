@@ -0,0 +1,246 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+
+use crate::memfs::{MemFs, NodeKind};
+use crate::{File, FileError};
+
+pub type WatchId = u64;
+
+type Callback = Arc<dyn Fn(&Notification) + Send + Sync>;
+
+static NEXT_WATCH_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum WatchEvent {
+  Opened,
+  Closed,
+  Written { offset: usize, len: usize },
+  Renamed { from: String, to: String },
+  Deleted,
+}
+
+/// What a watcher receives: the event and the file it happened to. `path` is the
+/// file name for `File::watch` and the full path for `MemFs::watch`.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Notification {
+  pub path: String,
+  pub event: WatchEvent,
+}
+
+#[derive(Clone)]
+pub enum Sink {
+  Channel(Sender<Notification>),
+  Callback(Callback),
+}
+
+#[derive(Clone)]
+struct Subscriber {
+  id: WatchId,
+  /// Reported instead of the file name, for directory watches.
+  path: Option<String>,
+  sink: Sink,
+}
+
+/// The subscribers attached to one `File`.
+#[derive(Default)]
+pub struct Watchers {
+  subscribers: Vec<Subscriber>,
+}
+
+/// A `MemFs::watch` registration, re-attached to files as they move in and out.
+#[derive(Clone)]
+pub struct DirWatch {
+  id: WatchId,
+  dir: String,
+  sink: Sink,
+}
+
+fn next_id() -> WatchId {
+  NEXT_WATCH_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+impl Sink {
+  /// Returns false once a channel's receiver has been dropped.
+  fn send(&self, notification: Notification) -> bool {
+    match self {
+      Sink::Channel(tx) => tx.send(notification).is_ok(),
+      Sink::Callback(cb) => {
+        cb(&notification);
+        true
+      }
+    }
+  }
+}
+
+impl fmt::Debug for Watchers {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Watchers({})", self.subscribers.len())
+  }
+}
+
+impl fmt::Debug for DirWatch {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("DirWatch").field("id", &self.id).field("dir", &self.dir).finish()
+  }
+}
+
+impl Watchers {
+  fn subscribe(&mut self, id: WatchId, path: Option<String>, sink: Sink) {
+    self.subscribers.push(Subscriber { id, path, sink });
+  }
+
+  fn unsubscribe(&mut self, id: WatchId) {
+    self.subscribers.retain(|s| s.id != id);
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.subscribers.is_empty()
+  }
+
+  /// Delivers `event` to every subscriber, dropping those whose receiver is gone.
+  pub fn notify(&mut self, name: &str, event: WatchEvent) {
+    self.subscribers.retain(|s| {
+      let path = s.path.clone().unwrap_or_else(|| name.to_string());
+      s.sink.send(Notification { path, event: event.clone() })
+    });
+  }
+}
+
+impl File {
+  /// Subscribes a channel to this file's events.
+  pub fn watch(&mut self) -> (WatchId, Receiver<Notification>) {
+    let (tx, rx) = mpsc::channel();
+    let id = next_id();
+    self.watchers.subscribe(id, None, Sink::Channel(tx));
+    (id, rx)
+  }
+
+  /// Subscribes a callback, run synchronously inside the operation that caused the event.
+  pub fn watch_with(&mut self, callback: impl Fn(&Notification) + Send + Sync + 'static) -> WatchId {
+    let id = next_id();
+    self.watchers.subscribe(id, None, Sink::Callback(Arc::new(callback)));
+    id
+  }
+
+  pub fn unwatch(&mut self, id: WatchId) {
+    self.watchers.unsubscribe(id);
+  }
+
+  pub fn notify(&mut self, event: WatchEvent) {
+    if !self.watchers.is_empty() {
+      let name = self.name.clone();
+      self.watchers.notify(&name, event);
+    }
+  }
+}
+
+fn is_under(path: &str, dir: &str) -> bool {
+  dir == "/" || path.strip_prefix(dir).is_some_and(|rest| rest.starts_with('/'))
+}
+
+impl MemFs {
+  /// Subscribes a channel to events on every file below `dir`, including files
+  /// created or moved there later.
+  pub fn watch(&mut self, dir: &str) -> Result<(WatchId, Receiver<Notification>), FileError> {
+    let (tx, rx) = mpsc::channel();
+    let id = self.add_dir_watch(dir, Sink::Channel(tx))?;
+    Ok((id, rx))
+  }
+
+  pub fn watch_with(&mut self, dir: &str, callback: impl Fn(&Notification) + Send + Sync + 'static) -> Result<WatchId, FileError> {
+    self.add_dir_watch(dir, Sink::Callback(Arc::new(callback)))
+  }
+
+  pub fn unwatch(&mut self, id: WatchId) {
+    self.dir_watches.retain(|w| w.id != id);
+    self.for_each_file_mut(|_, file| file.watchers.unsubscribe(id));
+  }
+
+  fn add_dir_watch(&mut self, dir: &str, sink: Sink) -> Result<WatchId, FileError> {
+    let meta = self.metadata(dir)?;
+    if meta.kind != NodeKind::Dir {
+      return Err(FileError::NotADirectory);
+    }
+    let dir = self.real_path(dir)?;
+    let id = next_id();
+    self.dir_watches.push(DirWatch { id, dir, sink });
+    self.rewatch();
+    Ok(id)
+  }
+
+  /// Re-attaches directory watches to the files currently below each watched
+  /// directory; called whenever files are added or moved.
+  pub(crate) fn rewatch(&mut self) {
+    if self.dir_watches.is_empty() {
+      return;
+    }
+    let watches = self.dir_watches.clone();
+    self.for_each_file_mut(|path, file| {
+      for watch in &watches {
+        file.watchers.unsubscribe(watch.id);
+        if is_under(&path, &watch.dir) {
+          file.watchers.subscribe(watch.id, Some(path.clone()), watch.sink.clone());
+        }
+      }
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Mutex;
+
+  #[test]
+  fn file_watch_sees_its_events() {
+    let mut f = File::new("a.txt");
+    let (id, rx) = f.watch();
+    f.open().unwrap();
+    f.write(b"hi").unwrap();
+    f.close().unwrap();
+    f.unwatch(id);
+    f.open().unwrap();
+    let events: Vec<WatchEvent> = rx.try_iter().map(|n| n.event).collect();
+    assert_eq!(events, [WatchEvent::Opened, WatchEvent::Written { offset: 0, len: 2 }, WatchEvent::Closed]);
+  }
+
+  #[test]
+  fn callbacks_run_inline() {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let mut f = File::new("a.txt");
+    let log = Arc::clone(&seen);
+    f.watch_with(move |n| log.lock().unwrap().push(n.path.clone()));
+    f.open().unwrap();
+    assert_eq!(*seen.lock().unwrap(), ["a.txt"]);
+  }
+
+  #[test]
+  fn dir_watch_follows_files_in_and_out() {
+    let mut fs = MemFs::new();
+    fs.mkdir("/in").unwrap();
+    fs.mkdir("/out").unwrap();
+    let (_, rx) = fs.watch("/in").unwrap();
+    fs.create("/in/a").unwrap().open().unwrap();
+    fs.rename("/in/a", "/out/a").unwrap();
+    fs.file_mut("/out/a").unwrap().close().unwrap();
+    fs.create("/out/b").unwrap();
+    fs.rename("/out/b", "/in/b").unwrap();
+    fs.remove("/in/b").unwrap();
+    let seen: Vec<Notification> = rx.try_iter().collect();
+    let renamed = WatchEvent::Renamed { from: "/in/a".into(), to: "/out/a".into() };
+    assert_eq!(seen, [
+      Notification { path: "/in/a".into(), event: WatchEvent::Opened },
+      Notification { path: "/in/a".into(), event: renamed },
+      Notification { path: "/in/b".into(), event: WatchEvent::Deleted },
+    ]);
+  }
+
+  #[test]
+  fn watching_a_file_path_fails() {
+    let mut fs = MemFs::new();
+    fs.create("/f").unwrap();
+    assert_eq!(fs.watch("/f").err(), Some(FileError::NotADirectory));
+  }
+}
@@ -0,0 +1,148 @@
+//! Extended attributes: free-form string key/value pairs on a `File`, kept in
+//! archives as `SCHILY.xattr.<key>` pax records the way GNU tar stores them.
+
+use std::collections::BTreeMap;
+
+use crate::memfs::{DirEntry, MemFs};
+use crate::perm::Access;
+use crate::state::FileState;
+use crate::{File, FileError};
+
+/// Keys the archive format uses for its own bookkeeping.
+pub const RESERVED_PREFIX: &str = "user.memfs.";
+
+pub type Xattrs = BTreeMap<String, String>;
+
+/// Keys must be non-empty and can't hold `=`, which ends a key in a pax record.
+fn check_key(key: &str) -> Result<(), FileError> {
+  if key.is_empty() || key.contains(['=', '\0']) || key.starts_with(RESERVED_PREFIX) {
+    return Err(FileError::InvalidXattr(key.to_string()));
+  }
+  Ok(())
+}
+
+impl File {
+  pub fn xattr(&self, key: &str) -> Option<&str> {
+    self.xattrs.get(key).map(String::as_str)
+  }
+
+  /// All attributes in key order.
+  pub fn xattrs(&self) -> impl Iterator<Item = (&str, &str)> {
+    self.xattrs.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+  }
+
+  /// Sets `key`, returning its old value. Needs write permission, not an open handle.
+  pub fn set_xattr(&mut self, key: &str, value: &str) -> Result<Option<String>, FileError> {
+    self.ensure_xattrs_writable()?;
+    check_key(key)?;
+    Ok(self.xattrs.insert(key.to_string(), value.to_string()))
+  }
+
+  pub fn remove_xattr(&mut self, key: &str) -> Result<Option<String>, FileError> {
+    self.ensure_xattrs_writable()?;
+    Ok(self.xattrs.remove(key))
+  }
+
+  fn ensure_xattrs_writable(&self) -> Result<(), FileError> {
+    if self.state == FileState::Deleted {
+      return Err(FileError::NotFound);
+    }
+    self.check_access(Access::Write)
+  }
+}
+
+impl MemFs {
+  /// Files that have `key`, and if `value` is given, have it set to that.
+  pub fn find_by_xattr(&self, key: &str, value: Option<&str>) -> Vec<(String, &File)> {
+    self.find(|file| match (file.xattr(key), value) {
+      (Some(found), Some(wanted)) => found == wanted,
+      (found, None) => found.is_some(),
+      (None, Some(_)) => false,
+    })
+  }
+
+  /// Every file matching `pred`, with its absolute path, in path order.
+  pub fn find(&self, pred: impl Fn(&File) -> bool) -> Vec<(String, &File)> {
+    self.walk().into_iter().filter_map(|(path, entry)| match entry {
+      DirEntry::File(file) if pred(file) => Some((path, file)),
+      _ => None,
+    }).collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::{Duration, SystemTime};
+
+  use crate::archive::{read_fs, write_fs};
+  use crate::perm::User;
+  use crate::time::Timestamps;
+
+  #[test]
+  fn set_get_and_remove() {
+    let mut f = File::new("f");
+    assert_eq!(f.set_xattr("user.team", "infra"), Ok(None));
+    assert_eq!(f.set_xattr("user.team", "web"), Ok(Some("infra".to_string())));
+    f.set_xattr("user.a", "").unwrap();
+    assert_eq!(f.xattrs().collect::<Vec<_>>(), [("user.a", ""), ("user.team", "web")]);
+    assert_eq!(f.remove_xattr("user.team"), Ok(Some("web".to_string())));
+    assert_eq!(f.xattr("user.team"), None);
+  }
+
+  #[test]
+  fn bad_keys_and_permissions() {
+    let mut f = File::new("f");
+    for key in ["", "a=b", "nul\0", "user.memfs.state"] {
+      assert_eq!(f.set_xattr(key, "x"), Err(FileError::InvalidXattr(key.to_string())));
+    }
+    f.chown("alice", "staff").unwrap();
+    f.chmod(0o644).unwrap();
+    f.set_user(User::new("bob", &[]));
+    assert_eq!(f.set_xattr("user.k", "v"), Err(FileError::PermissionDenied));
+    assert_eq!(f.remove_xattr("user.k"), Err(FileError::PermissionDenied));
+  }
+
+  #[test]
+  fn accessed_moves_on_read_only() {
+    let mut f = File::new_with_data("f", b"data");
+    let old = SystemTime::UNIX_EPOCH;
+    f.set_timestamps(Timestamps { created: old, modified: old, accessed: old });
+    let _ = f.contents();
+    assert_eq!(f.timestamps().accessed, old);
+    f.open_read().unwrap();
+    f.read(&mut [0; 2]).unwrap();
+    assert!(f.timestamps().accessed > old);
+    assert_eq!(f.timestamps().modified, old);
+  }
+
+  #[test]
+  fn queries_find_files() {
+    let mut fs = MemFs::new();
+    fs.mkdir("/d").unwrap();
+    for (path, team) in [("/d/a", "infra"), ("/d/b", "web"), ("/c", "infra")] {
+      fs.create(path).unwrap().set_xattr("user.team", team).unwrap();
+    }
+    fs.create("/e").unwrap();
+    let paths = |found: Vec<(String, &File)>| found.into_iter().map(|(p, _)| p).collect::<Vec<_>>();
+    assert_eq!(paths(fs.find_by_xattr("user.team", Some("infra"))), ["/c", "/d/a"]);
+    assert_eq!(paths(fs.find_by_xattr("user.team", None)).len(), 3);
+    let old = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+    fs.file_mut("/e").unwrap().set_timestamps(Timestamps { created: old, modified: old, accessed: old });
+    assert_eq!(paths(fs.modified_in(..SystemTime::UNIX_EPOCH + Duration::from_secs(2000))), ["/e"]);
+  }
+
+  #[test]
+  fn archives_keep_xattrs_and_times() {
+    let mut fs = MemFs::new();
+    let file = fs.create("/f").unwrap();
+    file.set_xattr("user.note", "multi\nline = value").unwrap();
+    let when = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    file.set_timestamps(Timestamps { created: when, modified: when, accessed: when });
+    let fs = read_fs(&write_fs(&fs, Vec::new()).unwrap()[..]).unwrap();
+    let file = fs.file("/f").unwrap();
+    assert_eq!(file.xattr("user.note"), Some("multi\nline = value"));
+    assert_eq!(file.timestamps().modified, when);
+    assert_eq!(file.timestamps().accessed, when);
+  }
+}